@@ -12,6 +12,7 @@
  * Reference: https://github.com/mystenlabs/nautilus
  */
 
+import { randomBytes } from 'crypto';
 import { logger } from '../utils/logger.js';
 import { config } from '../config/config.js';
 
@@ -136,6 +137,8 @@ export class NautilusService {
    * @param {string} params.ipTokenId - IP token ID
    * @param {string} params.name - IP name (e.g., "Chainsaw Man")
    * @param {string} params.source - Data source ('myanimelist', 'anilist', 'mangadex')
+   * @param {string} [params.nonce] - u64 nonce as a decimal string; random if omitted
+   * @param {number} [params.maxAgeMs] - Refetch upstream instead of serving cached data older than this
    * @returns {Promise<Object>} Signed metrics from Nautilus
    */
   async fetchExternalMetrics(params) {
@@ -146,7 +149,9 @@ export class NautilusService {
         return null;
       }
 
-      const { ipTokenId, name, source = 'myanimelist' } = params;
+      const { ipTokenId, name, source = 'myanimelist', maxAgeMs } = params;
+      // Unpredictable u64, sent as a string since it may not fit a JSON number.
+      const nonce = params.nonce ?? randomBytes(8).readBigUInt64BE().toString();
 
       if (!this.enclaveUrl) {
         logger.error('Nautilus Enclave URL is not configured.');
@@ -156,14 +161,19 @@ export class NautilusService {
       logger.info(`Fetching external metrics for ${name} from ${source} via Nautilus...`);
 
       // Prepare payload for Nautilus enclave
-      // The enclave will fetch data from external APIs and sign it
+      // The enclave will fetch data from external APIs and sign it.
+      // ip_token_id and nonce are signed into the response so the signature
+      // can't be replayed against another token or update transaction.
       const payload = {
         payload: {
-          ip_token_id: ipTokenId,
           name: name,
           source: source, // 'myanimelist', 'anilist', 'mangadex'
           timestamp: Date.now(),
         },
+        ip_token_id: ipTokenId,
+        nonce: nonce,
+        // Ask the enclave to refetch rather than re-sign older cached data.
+        max_age_ms: maxAgeMs,
       };
 
       const response = await fetch(`${this.enclaveUrl}/process_data`, {
@@ -176,7 +186,7 @@ export class NautilusService {
       });
 
       if (!response.ok) {
-        throw new Error(`Nautilus request failed: ${response.statusText}`);
+        throw await this.enclaveError(response);
       }
 
       const result = await response.json();
@@ -190,7 +200,7 @@ export class NautilusService {
       // Expected format:
       // {
       //   response: {
-      //     intent: number,
+      //     intent: number, // signing scope: 0 MyAnimeList, 1 AniList, 2 MangaDex
       //     timestamp_ms: number,
       //     data: {
       //       average_rating: number,
@@ -206,10 +216,13 @@ export class NautilusService {
         ipTokenId,
         name,
         source,
+        nonce,
         metrics: result.response.data,
         signature: result.signature,
         timestamp: result.response.timestamp_ms,
         intent: result.response.intent,
+        // Unsigned: { origin: 'upstream' | 'coalesced' | 'cache', fetched_at_ms, age_ms }
+        freshness: result.freshness,
       };
 
       logger.info(`External metrics fetched and signed for ${name}`);
@@ -220,10 +233,35 @@ export class NautilusService {
         this.metricsCollector.recordOperation('nautilus', 'fetch_metrics', duration, false);
       }
       logger.error(`Failed to fetch external metrics for ${params.name}:`, error);
-      throw new Error(`Failed to fetch external metrics: ${error.message}`);
+      const wrapped = new Error(`Failed to fetch external metrics: ${error.message}`);
+      wrapped.code = error.code;
+      wrapped.retryable = error.retryable ?? false;
+      throw wrapped;
     }
   }
 
+  /**
+   * Build an Error from an enclave error response.
+   * The enclave returns { error, code, retryable }; `retryable` is true for
+   * upstream outages (502, except `incomplete_record`), an open circuit
+   * breaker (503), request timeouts (504) and rate limiting (429) only.
+   * @param {Response} response - Non-OK fetch response
+   * @returns {Promise<Error>} Error with `status`, `code` and `retryable` set
+   */
+  async enclaveError(response) {
+    let body = {};
+    try {
+      body = await response.json();
+    } catch {
+      // Non-JSON body, e.g. from a proxy in front of the enclave
+    }
+    const error = new Error(`Nautilus request failed: ${body.error || response.statusText}`);
+    error.status = response.status;
+    error.code = body.code || 'unknown';
+    error.retryable = body.retryable ?? (response.status === 429 || response.status >= 502);
+    return error;
+  }
+
   /**
    * Fetch metrics from multiple external sources
    * Aggregates data from MyAnimeList, AniList, etc.
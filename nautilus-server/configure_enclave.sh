@@ -107,8 +107,8 @@ echo "Instance will be named: $FINAL_INSTANCE_NAME"
 # Read endpoints from allowed_endpoints.yaml
 #########################################
 if [ -f "$ALLOWLIST_PATH" ]; then
-    # Use a small Python snippet to parse the YAML and emit space-separated endpoints
-    ENDPOINTS=$(yq e '.endpoints | join(" ")' $ALLOWLIST_PATH 2>/dev/null)
+    # Emit space-separated hosts; entries are either a host or a map with a `host` key
+    ENDPOINTS=$(yq e '[.endpoints[] | (.host // .)] | join(" ")' $ALLOWLIST_PATH 2>/dev/null)
     if [ -n "$ENDPOINTS" ]; then
         echo "Endpoints found in $ALLOWLIST_PATH (before region patching):"
         echo "$ENDPOINTS"
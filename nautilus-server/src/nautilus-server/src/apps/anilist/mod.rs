@@ -0,0 +1,280 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::app::Source;
+use crate::common::IntentMessage;
+use crate::common::{
+    require, to_count, to_fixed_point, to_signed_response, IntentScope, ProcessedDataResponse,
+    RequestBinding, METRICS_PAYLOAD_VERSION, RATING_SCALE,
+};
+use crate::upstream::UpstreamClient;
+use crate::AppState;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Signed AniList metrics. All numeric fields are u64 fixed point so the
+/// BCS bytes can be decoded by the Move oracle.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AniListMetrics {
+    /// Payload layout version, see `METRICS_PAYLOAD_VERSION`.
+    pub version: u8,
+    /// IP token and nonce this response was requested for.
+    pub binding: RequestBinding,
+    pub title: String,
+    /// Weighted average score rescaled to 0-10 and scaled by `RATING_SCALE`
+    /// (AniList's 79/100 becomes 790).
+    pub average_score: u64,
+    /// Number of users with the media on their list.
+    pub popularity: u64,
+    pub favourites: u64,
+    /// Recent activity score computed by AniList.
+    pub trending: u64,
+    pub queried_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AniListRequest {
+    pub name: String,
+}
+
+/// AniList GraphQL endpoint, whose host is in allowed_endpoints.yaml.
+const ANILIST_API: &str = "https://graphql.anilist.co";
+
+const MEDIA_QUERY: &str = r#"
+query ($search: String) {
+  Media(search: $search, type: ANIME) {
+    id
+    title { romaji english }
+    averageScore
+    popularity
+    favourites
+    trending
+  }
+}
+"#;
+
+/// Look up `request.name` on AniList and sign the resulting metrics.
+pub async fn fetch_metrics(
+    state: &AppState,
+    request: AniListRequest,
+    binding: RequestBinding,
+    max_age_ms: Option<u64>,
+) -> Result<ProcessedDataResponse<IntentMessage<AniListMetrics>>, EnclaveError> {
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return Err(EnclaveError::InvalidInput("name required".to_string()));
+    }
+
+    let cache_key = format!("anilist:{}", name.to_lowercase());
+    let (freshness, fetched) = state
+        .cache
+        .get_or_fetch(Source::AniList, &cache_key, max_age_ms, {
+            let upstream = state.upstream.clone();
+            move || fetch_from_anilist(upstream, name)
+        })
+        .await?;
+    let metrics = AniListMetrics { binding, ..fetched };
+
+    Ok(to_signed_response(
+        &state.eph_kp,
+        metrics,
+        freshness.fetched_at_ms,
+        IntentScope::AniList,
+    )
+    .with_freshness(freshness))
+}
+
+/// Fetch and validate the metrics of `name`, unbound and unsigned.
+async fn fetch_from_anilist(
+    upstream: UpstreamClient,
+    name: String,
+) -> Result<AniListMetrics, EnclaveError> {
+    let anilist_api = upstream.api_base(ANILIST_API);
+    let url = reqwest::Url::parse(&anilist_api)
+        .map_err(|e| EnclaveError::Misconfigured(format!("invalid AniList API URL: {e}")))?;
+
+    let request = upstream
+        .post(url)
+        .header("Accept", "application/json")
+        .json(&json!({
+            "query": MEDIA_QUERY,
+            "variables": { "search": name },
+        }));
+    let json_body = upstream.get_json("AniList", request).await?;
+
+    let media = json_body
+        .get("data")
+        .and_then(|d| d.get("Media"))
+        .filter(|m| !m.is_null())
+        .ok_or_else(|| EnclaveError::NotFound(format!("no AniList match for '{name}'")))?;
+
+    let average_score = require(
+        media.get("averageScore").and_then(|v| v.as_f64()),
+        "AniList",
+        "averageScore",
+    )?;
+    let popularity = require(
+        media.get("popularity").and_then(|v| v.as_i64()),
+        "AniList",
+        "popularity",
+    )?;
+    let favourites = require(
+        media.get("favourites").and_then(|v| v.as_i64()),
+        "AniList",
+        "favourites",
+    )?;
+    let trending = require(
+        media.get("trending").and_then(|v| v.as_i64()),
+        "AniList",
+        "trending",
+    )?;
+    let title = media.get("title").and_then(|t| {
+        t.get("english")
+            .and_then(|v| v.as_str())
+            .or_else(|| t.get("romaji").and_then(|v| v.as_str()))
+    });
+    let title = require(title, "AniList", "title")?.to_string();
+
+    Ok(AniListMetrics {
+        version: METRICS_PAYLOAD_VERSION,
+        binding: RequestBinding::default(),
+        title,
+        average_score: to_fixed_point(average_score / 10.0, RATING_SCALE),
+        popularity: to_count(popularity),
+        favourites: to_count(favourites),
+        trending: to_count(trending),
+        queried_name: name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ObjectId;
+    use crate::test_utils::mock_provider;
+    use axum::{routing::post, Json, Router};
+    use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PrivateKey};
+    use fastcrypto::encoding::{Encoding, Hex};
+    use fastcrypto::traits::{KeyPair, ToFromBytes};
+
+    #[tokio::test]
+    async fn test_fetch_metrics_from_mock() {
+        async fn graphql() -> Json<serde_json::Value> {
+            Json(json!({
+                "data": {
+                    "Media": {
+                        "id": 20,
+                        "title": { "romaji": "Naruto", "english": "Naruto" },
+                        "averageScore": 79,
+                        "popularity": 512345,
+                        "favourites": 23456,
+                        "trending": 17
+                    }
+                }
+            }))
+        }
+
+        let state = mock_provider(Router::new().route("/", post(graphql))).await;
+        let signed = fetch_metrics(
+            &state,
+            AniListRequest {
+                name: "Naruto".to_string(),
+            },
+            RequestBinding::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let data = &signed.response.data;
+        assert_eq!(data.title, "Naruto");
+        assert_eq!(data.version, METRICS_PAYLOAD_VERSION);
+        assert_eq!(data.average_score, 790);
+        assert_eq!(data.popularity, 512345);
+        assert_eq!(data.favourites, 23456);
+        assert_eq!(data.trending, 17);
+        assert!(!signed.signature.is_empty());
+    }
+
+    /// Look up "Naruto" against a mock AniList answering every query with `body`.
+    async fn fetch_with_body(
+        body: serde_json::Value,
+    ) -> Result<ProcessedDataResponse<IntentMessage<AniListMetrics>>, EnclaveError> {
+        let router = Router::new().route("/", post(move || async move { Json(body) }));
+        let state = mock_provider(router).await;
+        let request = AniListRequest {
+            name: "Naruto".to_string(),
+        };
+        fetch_metrics(&state, request, RequestBinding::default(), None).await
+    }
+
+    #[tokio::test]
+    async fn test_null_media_is_not_found() {
+        let result = fetch_with_body(json!({ "data": { "Media": null } })).await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_graphql_errors_are_not_found() {
+        let result = fetch_with_body(json!({
+            "errors": [{ "message": "Not Found.", "status": 404 }],
+            "data": null
+        }))
+        .await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_missing_average_score_is_incomplete() {
+        let result = fetch_with_body(json!({
+            "data": {
+                "Media": {
+                    "id": 20,
+                    "title": { "romaji": "Naruto", "english": "Naruto" },
+                    "averageScore": null,
+                    "popularity": 512345,
+                    "favourites": 23456,
+                    "trending": 17
+                }
+            }
+        }))
+        .await;
+        assert!(
+            matches!(result, Err(EnclaveError::IncompleteRecord(ref msg)) if msg.contains("averageScore"))
+        );
+    }
+    // Test vector shared with smartcontract/odx/tests/nautilus_payload_tests.move.
+    // Signed with the ed25519 key whose seed is the bytes 0x00..=0x1f.
+    const VECTOR_PUBLIC_KEY: &str =
+        "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+    const VECTOR_PAYLOAD: &str = "010068e5cf8b01000006010b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b012a00000000000000064e617275746f160300000000000059d1070000000000a05b0000000000001100000000000000066e617275746f";
+    const VECTOR_SIGNATURE: &str = "f2b3fdc925986ccbe5445ca7ba28d0ba1639e163d98f4cd77cb3fc6afc077cde79a641df10febdb74b34caad0c574cd88978971e54a06ca50758f086477faf09";
+
+    #[test]
+    fn test_bcs_vector() {
+        let seed: Vec<u8> = (0u8..32).collect();
+        let kp = Ed25519KeyPair::from(Ed25519PrivateKey::from_bytes(&seed).unwrap());
+        let metrics = AniListMetrics {
+            version: METRICS_PAYLOAD_VERSION,
+            binding: RequestBinding {
+                ip_token_id: Some(ObjectId([0x0b; 32])),
+                nonce: Some(42),
+            },
+            title: "Naruto".to_string(),
+            average_score: to_fixed_point(79.0 / 10.0, RATING_SCALE),
+            popularity: 512_345,
+            favourites: 23_456,
+            trending: 17,
+            queried_name: "naruto".to_string(),
+        };
+        let signed = to_signed_response(&kp, metrics, 1_700_000_000_000, IntentScope::AniList);
+
+        assert_eq!(Hex::encode(kp.public().as_bytes()), VECTOR_PUBLIC_KEY);
+        assert_eq!(
+            Hex::encode(bcs::to_bytes(&signed.response).unwrap()),
+            VECTOR_PAYLOAD
+        );
+        assert_eq!(signed.signature, VECTOR_SIGNATURE);
+    }
+}
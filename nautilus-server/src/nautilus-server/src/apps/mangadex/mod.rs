@@ -0,0 +1,294 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::app::Source;
+use crate::common::IntentMessage;
+use crate::common::{
+    require, to_count, to_fixed_point, to_signed_response, IntentScope, ProcessedDataResponse,
+    RequestBinding, METRICS_PAYLOAD_VERSION, RATING_SCALE,
+};
+use crate::upstream::UpstreamClient;
+use crate::AppState;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+
+/// Signed MangaDex metrics. All numeric fields are u64 fixed point so the
+/// BCS bytes can be decoded by the Move oracle.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MangaDexMetrics {
+    /// Payload layout version, see `METRICS_PAYLOAD_VERSION`.
+    pub version: u8,
+    /// IP token and nonce this response was requested for.
+    pub binding: RequestBinding,
+    /// MangaDex manga UUID.
+    pub manga_id: String,
+    pub title: String,
+    /// Original language code, e.g. "ja" for manga or "ko" for manhwa.
+    pub original_language: String,
+    pub follows: u64,
+    /// Bayesian rating scaled by `RATING_SCALE` (905 = 9.05).
+    pub rating_bayesian: u64,
+    /// Mean rating scaled by `RATING_SCALE`.
+    pub rating_mean: u64,
+    pub comment_count: u64,
+    pub queried_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MangaDexRequest {
+    pub name: String,
+}
+
+/// MangaDex API root, whose host is in allowed_endpoints.yaml.
+const MANGADEX_API: &str = "https://api.mangadex.org";
+
+/// Resolve `request.name` to a MangaDex title and sign its statistics.
+pub async fn fetch_metrics(
+    state: &AppState,
+    request: MangaDexRequest,
+    binding: RequestBinding,
+    max_age_ms: Option<u64>,
+) -> Result<ProcessedDataResponse<IntentMessage<MangaDexMetrics>>, EnclaveError> {
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return Err(EnclaveError::InvalidInput("name required".to_string()));
+    }
+
+    let cache_key = format!("mangadex:{}", name.to_lowercase());
+    let (freshness, fetched) = state
+        .cache
+        .get_or_fetch(Source::MangaDex, &cache_key, max_age_ms, {
+            let upstream = state.upstream.clone();
+            move || fetch_from_mangadex(upstream, name)
+        })
+        .await?;
+    let metrics = MangaDexMetrics { binding, ..fetched };
+
+    Ok(to_signed_response(
+        &state.eph_kp,
+        metrics,
+        freshness.fetched_at_ms,
+        IntentScope::MangaDex,
+    )
+    .with_freshness(freshness))
+}
+
+/// Resolve `name` and fetch its statistics, unbound and unsigned.
+async fn fetch_from_mangadex(
+    upstream: UpstreamClient,
+    name: String,
+) -> Result<MangaDexMetrics, EnclaveError> {
+    let mangadex_api = upstream.api_base(MANGADEX_API);
+
+    // Resolve the title to a manga id.
+    let mut search_url = reqwest::Url::parse(&format!("{}/manga", mangadex_api))
+        .map_err(|e| EnclaveError::Misconfigured(format!("invalid MangaDex API URL: {e}")))?;
+    search_url
+        .query_pairs_mut()
+        .append_pair("title", &name)
+        .append_pair("limit", "1")
+        .append_pair("order[relevance]", "desc");
+
+    let search = upstream
+        .get_json("MangaDex", upstream.get(search_url))
+        .await?;
+    let manga = search
+        .get("data")
+        .and_then(|d| d.get(0))
+        .ok_or_else(|| EnclaveError::NotFound(format!("no MangaDex match for '{name}'")))?;
+
+    let manga_id = require(manga.get("id").and_then(|v| v.as_str()), "MangaDex", "id")?.to_string();
+    let attributes = require(manga.get("attributes"), "MangaDex", "attributes")?;
+    let title = attributes
+        .get("title")
+        .and_then(|t| {
+            t.get("en")
+                .or_else(|| t.as_object().and_then(|o| o.values().next()))
+        })
+        .and_then(|v| v.as_str());
+    let title = require(title, "MangaDex", "title")?.to_string();
+    let original_language = attributes.get("originalLanguage").and_then(|v| v.as_str());
+    let original_language = require(original_language, "MangaDex", "originalLanguage")?.to_string();
+
+    // Fetch statistics for the resolved id.
+    let stats_url = reqwest::Url::parse(&format!("{}/statistics/manga/{}", mangadex_api, manga_id))
+        .map_err(|e| EnclaveError::Misconfigured(format!("invalid MangaDex API URL: {e}")))?;
+    let stats_body = upstream
+        .get_json("MangaDex", upstream.get(stats_url))
+        .await?;
+    let stats = require(
+        stats_body.get("statistics").and_then(|s| s.get(&manga_id)),
+        "MangaDex",
+        "statistics",
+    )?;
+
+    let follows = require(
+        stats.get("follows").and_then(|v| v.as_i64()),
+        "MangaDex",
+        "follows",
+    )?;
+    let rating = require(stats.get("rating"), "MangaDex", "rating")?;
+    // Titles nobody has rated report a null average. They are not signed,
+    // since a zero rating would read as a real score.
+    let (Some(rating_bayesian), Some(rating_mean)) = (
+        rating.get("bayesian").and_then(|v| v.as_f64()),
+        rating.get("average").and_then(|v| v.as_f64()),
+    ) else {
+        return Err(EnclaveError::IncompleteRecord(format!(
+            "MangaDex title '{title}' has no rating yet"
+        )));
+    };
+    // MangaDex reports `comments: null` for titles without a comment thread,
+    // which genuinely means zero comments.
+    let comment_count = stats
+        .get("comments")
+        .and_then(|c| c.get("repliesCount"))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+
+    Ok(MangaDexMetrics {
+        version: METRICS_PAYLOAD_VERSION,
+        binding: RequestBinding::default(),
+        manga_id,
+        title,
+        original_language,
+        follows: to_count(follows),
+        rating_bayesian: to_fixed_point(rating_bayesian, RATING_SCALE),
+        rating_mean: to_fixed_point(rating_mean, RATING_SCALE),
+        comment_count: to_count(comment_count),
+        queried_name: name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ObjectId;
+    use crate::test_utils::mock_provider;
+    use axum::extract::{Path, Query};
+    use axum::{routing::get, Json, Router};
+    use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PrivateKey};
+    use fastcrypto::encoding::{Encoding, Hex};
+    use fastcrypto::traits::{KeyPair, ToFromBytes};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    const MANGA_ID: &str = "a1c7c817-4e59-43b7-9365-09675a149a6f";
+    const UNRATED_ID: &str = "0b1d3c5e-7f9a-4b2c-8d4e-6f8a0b2c4d6e";
+
+    #[tokio::test]
+    async fn test_fetch_metrics_from_mock() {
+        async fn search(Query(params): Query<HashMap<String, String>>) -> Json<serde_json::Value> {
+            let manga = match params.get("title").map(String::as_str) {
+                Some("Unrated") => json!({
+                    "id": UNRATED_ID,
+                    "type": "manga",
+                    "attributes": { "title": { "en": "Unrated" }, "originalLanguage": "ko" }
+                }),
+                Some("No Language") => json!({
+                    "id": MANGA_ID,
+                    "type": "manga",
+                    "attributes": { "title": { "en": "No Language" } }
+                }),
+                _ => json!({
+                    "id": MANGA_ID,
+                    "type": "manga",
+                    "attributes": {
+                        "title": { "en": "One Piece" },
+                        "originalLanguage": "ja"
+                    }
+                }),
+            };
+            Json(json!({ "result": "ok", "data": [manga] }))
+        }
+
+        async fn statistics(Path(id): Path<String>) -> Json<serde_json::Value> {
+            let rating = match id.as_str() {
+                UNRATED_ID => json!({ "average": null, "bayesian": 0 }),
+                _ => json!({ "average": 9.12, "bayesian": 9.05 }),
+            };
+            Json(json!({
+                "result": "ok",
+                "statistics": {
+                    id: {
+                        "comments": { "threadId": 4756728, "repliesCount": 12 },
+                        "rating": rating,
+                        "follows": 245678
+                    }
+                }
+            }))
+        }
+
+        let router = Router::new()
+            .route("/manga", get(search))
+            .route("/statistics/manga/:id", get(statistics));
+        let state = mock_provider(router).await;
+        let signed = fetch_metrics(
+            &state,
+            MangaDexRequest {
+                name: "One Piece".to_string(),
+            },
+            RequestBinding::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let data = &signed.response.data;
+        assert_eq!(data.manga_id, MANGA_ID);
+        assert_eq!(data.title, "One Piece");
+        assert_eq!(data.original_language, "ja");
+        assert_eq!(data.follows, 245678);
+        assert_eq!(data.rating_bayesian, 905);
+        assert_eq!(data.rating_mean, 912);
+        assert_eq!(data.comment_count, 12);
+        assert!(!signed.signature.is_empty());
+
+        // Unrated titles and records missing a signed field are not signed.
+        for name in ["Unrated", "No Language"] {
+            let request = MangaDexRequest {
+                name: name.to_string(),
+            };
+            let result = fetch_metrics(&state, request, RequestBinding::default(), None).await;
+            assert!(
+                matches!(result, Err(EnclaveError::IncompleteRecord(_))),
+                "{name} should not be signed"
+            );
+        }
+    }
+    // Test vector shared with smartcontract/odx/tests/nautilus_payload_tests.move.
+    // Signed with the ed25519 key whose seed is the bytes 0x00..=0x1f.
+    const VECTOR_PUBLIC_KEY: &str =
+        "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+    const VECTOR_PAYLOAD: &str = "020068e5cf8b01000006010b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b012a000000000000002461316337633831372d346535392d343362372d393336352d303936373561313439613666094f6e65205069656365026a61c0c204000000000089030000000000009003000000000000dc05000000000000096f6e65207069656365";
+    const VECTOR_SIGNATURE: &str = "009a183211c19a825a155a7d93975b2eb6688c9cfea839e0cb8e9b61ec53348e43f678ce0864d5a11681c59577a423400e705fde15e4edcb5b35bd7544539a0b";
+
+    #[test]
+    fn test_bcs_vector() {
+        let seed: Vec<u8> = (0u8..32).collect();
+        let kp = Ed25519KeyPair::from(Ed25519PrivateKey::from_bytes(&seed).unwrap());
+        let metrics = MangaDexMetrics {
+            version: METRICS_PAYLOAD_VERSION,
+            binding: RequestBinding {
+                ip_token_id: Some(ObjectId([0x0b; 32])),
+                nonce: Some(42),
+            },
+            manga_id: MANGA_ID.to_string(),
+            title: "One Piece".to_string(),
+            original_language: "ja".to_string(),
+            follows: 312_000,
+            rating_bayesian: to_fixed_point(9.05, RATING_SCALE),
+            rating_mean: to_fixed_point(9.12, RATING_SCALE),
+            comment_count: 1_500,
+            queried_name: "one piece".to_string(),
+        };
+        let signed = to_signed_response(&kp, metrics, 1_700_000_000_000, IntentScope::MangaDex);
+
+        assert_eq!(Hex::encode(kp.public().as_bytes()), VECTOR_PUBLIC_KEY);
+        assert_eq!(
+            Hex::encode(bcs::to_bytes(&signed.response).unwrap()),
+            VECTOR_PAYLOAD
+        );
+        assert_eq!(signed.signature, VECTOR_SIGNATURE);
+    }
+}
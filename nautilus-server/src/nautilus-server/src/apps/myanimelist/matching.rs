@@ -0,0 +1,236 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Best-match resolution of a queried name against MAL search candidates.
+
+use crate::EnclaveError;
+use serde_json::Value;
+
+/// Number of candidates requested from the MAL search endpoint.
+pub const SEARCH_LIMIT: usize = 10;
+
+/// Lowest similarity accepted as a match, in [0, 1].
+pub const MIN_MATCH_CONFIDENCE: f64 = 0.6;
+
+/// The best candidate must beat the runner-up by more than this margin,
+/// otherwise the query is too ambiguous to sign.
+pub const AMBIGUITY_MARGIN: f64 = 0.05;
+
+/// Optional filters applied to candidates before scoring.
+#[derive(Debug, Default)]
+pub struct MatchFilters<'a> {
+    /// MAL media type, e.g. `tv`, `movie`, `ova`. Compared case-insensitively.
+    pub media_type: Option<&'a str>,
+    /// Year the title started airing or publishing.
+    pub start_year: Option<u32>,
+}
+
+/// Pick the candidate node whose main, English, Japanese or synonym title is
+/// closest to `query`. Returns the node and its similarity in [0, 1].
+pub fn best_match<'a>(
+    query: &str,
+    nodes: &[&'a Value],
+    filters: &MatchFilters,
+) -> Result<(&'a Value, f64), EnclaveError> {
+    let normalized = normalize(query);
+    let mut scored: Vec<(&Value, f64)> = nodes
+        .iter()
+        .filter(|node| passes_filters(node, filters))
+        .map(|node| {
+            let score = titles(node)
+                .iter()
+                .map(|title| similarity(&normalized, &normalize(title)))
+                .fold(0.0, f64::max);
+            (*node, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let Some(&(best, confidence)) = scored.first() else {
+        return Err(EnclaveError::NotFound(format!(
+            "no MAL match for '{query}'"
+        )));
+    };
+    if confidence < MIN_MATCH_CONFIDENCE {
+        return Err(EnclaveError::NotFound(format!(
+            "no MAL title is close enough to '{query}'"
+        )));
+    }
+    if let Some(&(runner_up, score)) = scored.get(1) {
+        if confidence - score <= AMBIGUITY_MARGIN {
+            return Err(EnclaveError::Ambiguous(format!(
+                "'{query}' matches both {} and {}, narrow it with media_type or start_year",
+                describe(best),
+                describe(runner_up)
+            )));
+        }
+    }
+    Ok((best, confidence))
+}
+
+fn passes_filters(node: &Value, filters: &MatchFilters) -> bool {
+    if let Some(media_type) = filters.media_type {
+        let matches = node
+            .get("media_type")
+            .and_then(|v| v.as_str())
+            .is_some_and(|t| t.eq_ignore_ascii_case(media_type));
+        if !matches {
+            return false;
+        }
+    }
+    if let Some(year) = filters.start_year {
+        // start_date is "YYYY", "YYYY-MM" or "YYYY-MM-DD".
+        let matches = node
+            .get("start_date")
+            .and_then(|v| v.as_str())
+            .and_then(|d| d.get(..4))
+            .and_then(|y| y.parse::<u32>().ok())
+            == Some(year);
+        if !matches {
+            return false;
+        }
+    }
+    true
+}
+
+/// Main, English, Japanese and synonym titles of a search node.
+fn titles(node: &Value) -> Vec<&str> {
+    let mut titles: Vec<&str> = node
+        .get("title")
+        .and_then(|v| v.as_str())
+        .into_iter()
+        .collect();
+    if let Some(alternative) = node.get("alternative_titles") {
+        titles.extend(
+            ["en", "ja"]
+                .iter()
+                .filter_map(|k| alternative.get(k)?.as_str()),
+        );
+        if let Some(synonyms) = alternative.get("synonyms").and_then(|v| v.as_array()) {
+            titles.extend(synonyms.iter().filter_map(|v| v.as_str()));
+        }
+    }
+    titles.retain(|t| !t.trim().is_empty());
+    titles
+}
+
+fn describe(node: &Value) -> String {
+    let title = node.get("title").and_then(|v| v.as_str()).unwrap_or("?");
+    let id = node.get("id").and_then(|v| v.as_u64()).unwrap_or_default();
+    format!("'{title}' (mal_id {id})")
+}
+
+/// Lowercase, replace punctuation with spaces and collapse whitespace, so
+/// "Naruto: Shippuden" and "naruto shippuden" compare equal.
+fn normalize(title: &str) -> String {
+    title
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Levenshtein similarity in [0, 1], computed over chars.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 0.0;
+    }
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    1.0 - previous[b.len()] as f64 / longest as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn node(id: u64, title: &str, en: &str, media_type: &str, start_date: &str) -> Value {
+        json!({
+            "id": id,
+            "title": title,
+            "alternative_titles": { "en": en, "ja": "", "synonyms": [] },
+            "media_type": media_type,
+            "start_date": start_date,
+        })
+    }
+
+    #[test]
+    fn test_best_match_prefers_closest_title() {
+        let recap = node(
+            1,
+            "Naruto: Shippuuden",
+            "Naruto Shippuden",
+            "tv",
+            "2007-02-15",
+        );
+        let main = node(20, "Naruto", "Naruto", "tv", "2002-10-03");
+        let (best, confidence) =
+            best_match("naruto", &[&recap, &main], &MatchFilters::default()).unwrap();
+        assert_eq!(best["id"], 20);
+        assert_eq!(confidence, 1.0);
+    }
+
+    #[test]
+    fn test_best_match_uses_alternative_titles() {
+        let node = node(
+            16498,
+            "Shingeki no Kyojin",
+            "Attack on Titan",
+            "tv",
+            "2013-04-07",
+        );
+        let (best, _) = best_match("Attack on Titan", &[&node], &MatchFilters::default()).unwrap();
+        assert_eq!(best["id"], 16498);
+    }
+
+    #[test]
+    fn test_best_match_filters_and_ambiguity() {
+        let tv = node(1, "Hunter x Hunter", "Hunter x Hunter", "tv", "1999-10-16");
+        let remake = node(
+            11061,
+            "Hunter x Hunter (2011)",
+            "Hunter x Hunter",
+            "tv",
+            "2011-10-02",
+        );
+        let result = best_match("Hunter x Hunter", &[&tv, &remake], &MatchFilters::default());
+        assert!(matches!(result, Err(EnclaveError::Ambiguous(_))));
+
+        let filters = MatchFilters {
+            start_year: Some(2011),
+            ..Default::default()
+        };
+        let (best, _) = best_match("Hunter x Hunter", &[&tv, &remake], &filters).unwrap();
+        assert_eq!(best["id"], 11061);
+
+        let filters = MatchFilters {
+            media_type: Some("movie"),
+            ..Default::default()
+        };
+        let result = best_match("Hunter x Hunter", &[&tv, &remake], &filters);
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity("naruto", "naruto"), 1.0);
+        assert_eq!(similarity("", ""), 0.0);
+        assert!(similarity("naruto", "boruto") < 1.0);
+        assert_eq!(normalize("  Naruto:  Shippūden! "), "naruto shippūden");
+    }
+}
@@ -1,163 +1,733 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::common::{to_signed_response, IntentScope, ProcessDataRequest, ProcessedDataResponse};
+use crate::app::Source;
 use crate::common::IntentMessage;
+use crate::common::{
+    require, to_count, to_fixed_point, to_signed_response, IntentScope, ProcessedDataResponse,
+    RequestBinding, MATCH_CONFIDENCE_SCALE, METRICS_PAYLOAD_VERSION, RATING_SCALE,
+};
+use crate::upstream::UpstreamClient;
 use crate::AppState;
 use crate::EnclaveError;
-use axum::extract::State;
-use axum::Json;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use lazy_static::lazy_static;
-use std::collections::HashMap;
-use tokio::sync::Mutex;
 
+mod matching;
+
+use matching::{best_match, MatchFilters, SEARCH_LIMIT};
+
+/// Signed MyAnimeList metrics. All numeric fields are u64 fixed point so the
+/// BCS bytes can be decoded by the Move oracle.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MyMetrics {
+    /// Payload layout version, see `METRICS_PAYLOAD_VERSION`.
+    pub version: u8,
+    /// IP token and nonce this response was requested for.
+    pub binding: RequestBinding,
+    /// Catalogue `mal_id` belongs to, so a manga measurement cannot be
+    /// passed off as an anime one.
+    pub media_kind: MediaKind,
+    /// MAL id of the measured title, either requested or resolved from the name.
+    pub mal_id: u64,
     pub title: String,
-    pub external_average_rating: f64,
-    pub external_popularity_rank: i64,
-    pub external_member_count: i64,
+    /// MAL mean score scaled by `RATING_SCALE` (850 = 8.50).
+    pub external_average_rating: u64,
+    pub external_popularity_rank: u64,
+    pub external_member_count: u64,
     pub queried_name: String,
+    /// Title similarity of the match scaled by `MATCH_CONFIDENCE_SCALE`.
+    pub match_confidence: u64,
+    pub extended: MalExtendedMetrics,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MyAnimeRequest {
-    pub name: String,
-}
-
-const CACHE_TTL_SECS: u64 = 300; // 5 minutes
+/// Layout version of `MalExtendedMetrics`, bumped independently of
+/// `METRICS_PAYLOAD_VERSION` when extended fields are added.
+pub const EXTENDED_METRICS_VERSION: u8 = 2;
 
-lazy_static! {
-    static ref CACHE: Mutex<HashMap<String, (u64, serde_json::Value)>> = Mutex::new(HashMap::new());
+/// Kind of a measured MAL title. Variant order matches
+/// `odx::datatypes::CATEGORY_ANIME`, `CATEGORY_MANGA` and `CATEGORY_MANHWA`,
+/// so Move can peel the BCS tag as a u8 category.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    #[default]
+    Anime,
+    /// Manga, light novels and other print media except manhwa.
+    Manga,
+    /// Titles of the manga catalogue whose `media_type` is `manhwa`.
+    Manhwa,
 }
 
-pub async fn process_data(
-    State(state): State<Arc<AppState>>,
-    Json(request): Json<ProcessDataRequest<MyAnimeRequest>>,
-) -> Result<Json<ProcessedDataResponse<IntentMessage<MyMetrics>>>, EnclaveError> {
-    let name = request.payload.name.trim().to_string();
-    if name.is_empty() {
-        return Err(EnclaveError::GenericError("name required".to_string()));
+impl MediaKind {
+    /// MAL API path segment, `anime` or `manga`. Manhwa is part of the manga
+    /// catalogue.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Anime => "anime",
+            MediaKind::Manga | MediaKind::Manhwa => "manga",
+        }
+    }
+
+    /// Fields requested for every measured title of this kind.
+    fn metric_fields(&self) -> String {
+        let specific = match self {
+            MediaKind::Anime => "statistics,num_episodes",
+            MediaKind::Manga | MediaKind::Manhwa => "num_volumes,num_chapters",
+        };
+        format!("{METRIC_FIELDS},{specific}")
     }
 
-    let cache_key = format!("mal:{}", name.to_lowercase());
-    // check cache
-    if let Some((ts, cached)) = {
-        let c = CACHE.lock().await;
-        c.get(&cache_key).cloned()
-    } {
-        if current_secs() < ts + CACHE_TTL_SECS {
-            // Convert cached attested response back to ProcessedDataResponse shape
-            let pd: ProcessedDataResponse<IntentMessage<MyMetrics>> = serde_json::from_value(cached)
-                .map_err(|e| EnclaveError::GenericError(format!("cache deserialize failed: {e}")))?;
-            return Ok(Json(pd));
+    /// Kind of `node`, a title of this kind's catalogue, from its `media_type`.
+    fn of_node(&self, node: &serde_json::Value) -> MediaKind {
+        match self {
+            MediaKind::Anime => MediaKind::Anime,
+            MediaKind::Manga | MediaKind::Manhwa => {
+                let media_type = node.get("media_type").and_then(|v| v.as_str());
+                if media_type.is_some_and(|t| t.eq_ignore_ascii_case("manhwa")) {
+                    MediaKind::Manhwa
+                } else {
+                    MediaKind::Manga
+                }
+            }
         }
     }
+}
 
-    let mal_api = std::env::var("MAL_API_URL").unwrap_or_else(|_| "https://api.myanimelist.net/v2".to_string());
-    let client_id = std::env::var("MAL_CLIENT_ID").ok();
-    let bearer = std::env::var("MAL_BEARER_TOKEN").ok();
+/// Engagement and airing details used to compute ratios such as completion
+/// or drop rate. Optional fields are `None` when MAL does not report them for
+/// the title, e.g. `rank` for unranked titles or `num_episodes` while airing.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MalExtendedMetrics {
+    /// Layout version, see `EXTENDED_METRICS_VERSION`.
+    pub version: u8,
+    /// Rank by score.
+    pub rank: Option<u64>,
+    pub num_scoring_users: u64,
+    pub num_favorites: Option<u64>,
+    /// List entries per status, from `statistics.status`. MAL only reports
+    /// it for anime, so it is always present for anime and `None` for manga.
+    pub list_status: Option<ListStatusCounts>,
+    /// Airing or publishing status, e.g. `finished_airing` or `currently_publishing`.
+    pub status: String,
+    /// Start date as reported by MAL: `YYYY-MM-DD`, `YYYY-MM` or `YYYY`.
+    pub start_date: Option<String>,
+    /// Anime only.
+    pub num_episodes: Option<u64>,
+    /// Manga only.
+    pub num_volumes: Option<u64>,
+    /// Manga only.
+    pub num_chapters: Option<u64>,
+}
 
-    let mut url = match reqwest::Url::parse(&format!("{}/anime", mal_api)) {
-        Ok(u) => u,
-        Err(e) => return Err(EnclaveError::GenericError(format!("invalid MAL_API_URL: {e}"))),
-    };
-    url.query_pairs_mut()
-        .append_pair("q", &name)
-        .append_pair("limit", "1")
-        .append_pair("fields", "mean,popularity,num_list_users");
+/// Number of MAL list entries in each status.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ListStatusCounts {
+    pub watching: u64,
+    pub completed: u64,
+    pub on_hold: u64,
+    pub dropped: u64,
+    pub plan_to_watch: u64,
+}
 
-    let client = reqwest::Client::new();
-    let mut req_builder = client.get(url);
-    if let Some(cid) = client_id {
-        req_builder = req_builder.header("X-MAL-Client-ID", cid);
-    } else if let Some(token) = bearer {
-        req_builder = req_builder.bearer_auth(token);
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MyAnimeRequest {
+    /// Free-text title to search for. Ignored for matching when `mal_id` is set.
+    #[serde(default)]
+    pub name: String,
+    /// Stable MAL id, looked up directly instead of searching by name.
+    #[serde(default)]
+    pub mal_id: Option<u64>,
+    /// Catalogue to query, `anime` (default), `manga`, or `manhwa` for the
+    /// manga catalogue narrowed to manhwa.
+    #[serde(default)]
+    pub media_kind: MediaKind,
+    /// Only match this MAL media type, e.g. `tv`, `movie` or `light_novel`.
+    #[serde(default)]
+    pub media_type: Option<String>,
+    /// Only match titles that started in this year.
+    #[serde(default)]
+    pub start_year: Option<u32>,
+}
+
+impl MyAnimeRequest {
+    /// `media_type` with blank values treated as unset, defaulting to
+    /// `manhwa` for the manhwa kind.
+    fn media_type_filter(&self) -> Option<&str> {
+        self.media_type
+            .as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .or(match self.media_kind {
+                MediaKind::Manhwa => Some("manhwa"),
+                _ => None,
+            })
     }
+}
+
+/// MyAnimeList API root, whose host is in allowed_endpoints.yaml.
+const MAL_API: &str = "https://api.myanimelist.net/v2";
 
-    let resp = req_builder
-        .send()
-        .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to request MAL: {e}")))?;
+/// Fields requested for every measured title, see `MediaKind::metric_fields`.
+const METRIC_FIELDS: &str = concat!(
+    "media_type,mean,popularity,num_list_users,",
+    "rank,num_scoring_users,num_favorites,status,start_date"
+);
 
-    if !resp.status().is_success() {
-        return Err(EnclaveError::GenericError(format!("MAL returned status {}", resp.status())));
+/// Look up `request.mal_id`, or else the best match for `request.name`, on
+/// MyAnimeList and sign the resulting metrics. Cached metrics older than
+/// `max_age_ms` are fetched again.
+pub async fn fetch_metrics(
+    state: &AppState,
+    request: MyAnimeRequest,
+    binding: RequestBinding,
+    max_age_ms: Option<u64>,
+) -> Result<ProcessedDataResponse<IntentMessage<MyMetrics>>, EnclaveError> {
+    let name = request.name.trim().to_string();
+    if name.is_empty() && request.mal_id.is_none() {
+        return Err(EnclaveError::InvalidInput(
+            "name or mal_id required".to_string(),
+        ));
     }
 
-    let json_body: serde_json::Value = resp
-        .json()
-        .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to parse MAL JSON: {e}")))?;
+    let kind = request.media_kind;
+    let cache_key = match request.mal_id {
+        Some(mal_id) => format!("mal:{}:id:{mal_id}", kind.as_str()),
+        None => format!(
+            "mal:{}:{}|{}|{}",
+            kind.as_str(),
+            name.to_lowercase(),
+            request
+                .media_type_filter()
+                .unwrap_or_default()
+                .to_lowercase(),
+            request
+                .start_year
+                .map(|y| y.to_string())
+                .unwrap_or_default()
+        ),
+    };
+
+    // Concurrent requests for the same key share one upstream fetch; each
+    // caller signs the result with its own binding.
+    let fetch = {
+        let upstream = state.upstream.clone();
+        let name = name.clone();
+        move || fetch_from_mal(upstream, request, name)
+    };
+    let (freshness, fetched) = state
+        .cache
+        .get_or_fetch(Source::MyAnimeList, &cache_key, max_age_ms, fetch)
+        .await?;
+    let metrics = MyMetrics {
+        binding,
+        queried_name: name,
+        ..fetched
+    };
+
+    Ok(to_signed_response(
+        &state.eph_kp,
+        metrics,
+        freshness.fetched_at_ms,
+        IntentScope::MyAnimeList,
+    )
+    .with_freshness(freshness))
+}
 
-    let data0 = json_body
-        .get("data")
-        .and_then(|d| d.get(0))
-        .and_then(|n| n.get("node"))
-        .cloned()
-        .unwrap_or_default();
+/// Fetch and validate the metrics of the requested title, unbound and unsigned.
+async fn fetch_from_mal(
+    upstream: UpstreamClient,
+    request: MyAnimeRequest,
+    name: String,
+) -> Result<MyMetrics, EnclaveError> {
+    let kind = request.media_kind;
+    let mal_api = upstream.api_base(MAL_API);
 
-    let mean = data0.get("mean").and_then(|v| v.as_f64()).unwrap_or(0.0);
-    let popularity = data0.get("popularity").and_then(|v| v.as_i64()).unwrap_or(0);
-    let num_list_users = data0.get("num_list_users").and_then(|v| v.as_i64()).unwrap_or(0);
-    let title = data0.get("title").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let (node, confidence) = match request.mal_id {
+        // A stable id names the entity exactly, no matching involved.
+        Some(mal_id) => {
+            let mut url = reqwest::Url::parse(&format!("{}/{}/{}", mal_api, kind.as_str(), mal_id))
+                .map_err(|e| EnclaveError::Misconfigured(format!("invalid MAL API URL: {e}")))?;
+            url.query_pairs_mut()
+                .append_pair("fields", &kind.metric_fields());
+            (get_json(&upstream, url).await?, 1.0)
+        }
+        None => {
+            let mut url = reqwest::Url::parse(&format!("{}/{}", mal_api, kind.as_str()))
+                .map_err(|e| EnclaveError::Misconfigured(format!("invalid MAL API URL: {e}")))?;
+            url.query_pairs_mut()
+                .append_pair("q", &name)
+                .append_pair("limit", &SEARCH_LIMIT.to_string())
+                .append_pair(
+                    "fields",
+                    &format!("alternative_titles,{}", kind.metric_fields()),
+                );
+            let json_body = get_json(&upstream, url).await?;
 
-    let metrics = MyMetrics {
-        title: title.clone(),
-        external_average_rating: mean,
-        external_popularity_rank: popularity,
-        external_member_count: num_list_users,
-        queried_name: name.clone(),
+            let nodes: Vec<&serde_json::Value> = json_body
+                .get("data")
+                .and_then(|d| d.as_array())
+                .map(|d| d.iter().filter_map(|n| n.get("node")).collect())
+                .unwrap_or_default();
+            let filters = MatchFilters {
+                media_type: request.media_type_filter(),
+                start_year: request.start_year,
+            };
+            let (node, confidence) = best_match(&name, &nodes, &filters)?;
+            (node.clone(), confidence)
+        }
     };
 
-    let timestamp_ms = current_millis() as u64;
-    let signed = to_signed_response(&state.eph_kp, metrics.clone(), timestamp_ms, IntentScope::ProcessData);
+    let mal_id = require(node.get("id").and_then(|v| v.as_u64()), "MAL", "id")?;
+    let mean = require(node.get("mean").and_then(|v| v.as_f64()), "MAL", "mean")?;
+    let popularity = require(
+        node.get("popularity").and_then(|v| v.as_i64()),
+        "MAL",
+        "popularity",
+    )?;
+    let num_list_users = require(
+        node.get("num_list_users").and_then(|v| v.as_i64()),
+        "MAL",
+        "num_list_users",
+    )?;
+    let title = require(node.get("title").and_then(|v| v.as_str()), "MAL", "title")?.to_string();
+    let kind = kind.of_node(&node);
+    let extended = extended_metrics(&node, kind)?;
 
-    // cache the serialized signed response
-    let serialized = serde_json::to_value(&signed).map_err(|e| EnclaveError::GenericError(format!("serialize failed: {e}")))?;
-    {
-        let mut c = CACHE.lock().await;
-        c.insert(cache_key, (current_secs(), serialized.clone()));
-    }
+    Ok(MyMetrics {
+        version: METRICS_PAYLOAD_VERSION,
+        binding: RequestBinding::default(),
+        media_kind: kind,
+        mal_id,
+        title,
+        external_average_rating: to_fixed_point(mean, RATING_SCALE),
+        external_popularity_rank: to_count(popularity),
+        external_member_count: to_count(num_list_users),
+        queried_name: name,
+        match_confidence: to_fixed_point(confidence, MATCH_CONFIDENCE_SCALE),
+        extended,
+    })
+}
+
+/// Read the extended metrics of a MAL anime or manga node.
+fn extended_metrics(
+    node: &serde_json::Value,
+    kind: MediaKind,
+) -> Result<MalExtendedMetrics, EnclaveError> {
+    let list_status = match kind {
+        MediaKind::Anime => Some(list_status_counts(node)?),
+        MediaKind::Manga | MediaKind::Manhwa => None,
+    };
+    // MAL reports 0 episodes, volumes or chapters when the count is not known yet.
+    let known_count = |key: &str| node.get(key).and_then(count).filter(|n| *n > 0);
 
-    Ok(Json(signed))
+    Ok(MalExtendedMetrics {
+        version: EXTENDED_METRICS_VERSION,
+        rank: node.get("rank").and_then(count),
+        num_scoring_users: require(
+            node.get("num_scoring_users").and_then(count),
+            "MAL",
+            "num_scoring_users",
+        )?,
+        num_favorites: node.get("num_favorites").and_then(count),
+        list_status,
+        status: require(node.get("status").and_then(|v| v.as_str()), "MAL", "status")?.to_string(),
+        start_date: node
+            .get("start_date")
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        num_episodes: known_count("num_episodes"),
+        num_volumes: known_count("num_volumes"),
+        num_chapters: known_count("num_chapters"),
+    })
 }
 
-fn current_secs() -> u64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+fn list_status_counts(node: &serde_json::Value) -> Result<ListStatusCounts, EnclaveError> {
+    let status_counts = node.get("statistics").and_then(|s| s.get("status"));
+    let status_count = |key: &str| {
+        require(
+            status_counts.and_then(|s| s.get(key)).and_then(count),
+            "MAL",
+            &format!("statistics.status.{key}"),
+        )
+    };
+
+    Ok(ListStatusCounts {
+        watching: status_count("watching")?,
+        completed: status_count("completed")?,
+        on_hold: status_count("on_hold")?,
+        dropped: status_count("dropped")?,
+        plan_to_watch: status_count("plan_to_watch")?,
+    })
 }
 
-fn current_millis() -> u128 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+/// Read a MAL count, which `statistics` reports as a numeric string.
+fn count(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::String(s) => s.parse().ok(),
+        v => v.as_i64().map(to_count),
+    }
+}
+
+/// GET a MAL API url with the configured credentials.
+async fn get_json(
+    upstream: &UpstreamClient,
+    url: reqwest::Url,
+) -> Result<serde_json::Value, EnclaveError> {
+    let client_id = std::env::var("MAL_CLIENT_ID").ok();
+    let bearer = std::env::var("MAL_BEARER_TOKEN").ok();
+
+    let mut req_builder = upstream.get(url);
+    if let Some(cid) = client_id {
+        // Sensitive values print as "Sensitive" in any Debug output.
+        let mut value = reqwest::header::HeaderValue::from_str(&cid).map_err(|_| {
+            EnclaveError::Misconfigured("MAL_CLIENT_ID is not a valid header value".to_string())
+        })?;
+        value.set_sensitive(true);
+        req_builder = req_builder.header("X-MAL-Client-ID", value);
+    } else if let Some(token) = bearer {
+        req_builder = req_builder.bearer_auth(token);
+    }
+
+    upstream.get_json("MAL", req_builder).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::AppState;
-    use fastcrypto::ed25519::Ed25519KeyPair;
-    use std::sync::Arc;
+    use crate::cache::{current_millis, CacheOrigin};
+    use crate::common::ObjectId;
+    use crate::test_utils::{app_state, mock_provider};
+    use axum::extract::{Path, Query};
+    use axum::http::StatusCode;
+    use axum::{routing::get, Json, Router};
+    use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PrivateKey};
+    use fastcrypto::encoding::{Encoding, Hex};
+    use fastcrypto::traits::{KeyPair, ToFromBytes};
+    use std::collections::HashMap;
 
     #[tokio::test]
     async fn test_signature_roundtrip() {
-        let state = Arc::new(AppState {
-            eph_kp: Ed25519KeyPair::generate(&mut rand::thread_rng()),
-            api_key: "".to_string(),
-        });
+        let state = app_state(UpstreamClient::for_tests());
 
-        let req = ProcessDataRequest { payload: MyAnimeRequest { name: "Test".to_string() } };
         // We won't call MAL in unit test; instead create metrics and sign directly to ensure no panic.
         let metrics = MyMetrics {
+            version: METRICS_PAYLOAD_VERSION,
+            binding: RequestBinding::default(),
+            media_kind: MediaKind::Anime,
+            mal_id: 1,
             title: "Test".to_string(),
-            external_average_rating: 8.5,
+            external_average_rating: to_fixed_point(8.5, RATING_SCALE),
             external_popularity_rank: 123,
             external_member_count: 1000,
             queried_name: "test".to_string(),
+            match_confidence: MATCH_CONFIDENCE_SCALE,
+            extended: naruto_extended(),
         };
-        let signed = to_signed_response(&state.eph_kp, metrics, current_millis() as u64, IntentScope::ProcessData);
+        let signed = to_signed_response(
+            &state.eph_kp,
+            metrics,
+            current_millis(),
+            IntentScope::MyAnimeList,
+        );
         assert!(!signed.signature.is_empty());
+        assert_eq!(signed.response.data.external_average_rating, 850);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metrics_from_mock() {
+        async fn search(Query(params): Query<HashMap<String, String>>) -> Json<serde_json::Value> {
+            match params.get("q").map(String::as_str) {
+                Some("Unaired") => Json(serde_json::json!({
+                    "data": [{ "node": { "id": 1, "title": "Unaired", "popularity": 9000, "num_list_users": 10 } }]
+                })),
+                _ => Json(serde_json::json!({ "data": [] })),
+            }
+        }
+
+        async fn anime(Path(id): Path<u64>) -> Result<Json<serde_json::Value>, StatusCode> {
+            match id {
+                20 => Ok(Json(serde_json::json!({
+                    "id": 20,
+                    "title": "Naruto",
+                    "mean": 7.98,
+                    "popularity": 8,
+                    "num_list_users": 2900000,
+                    "rank": 660,
+                    "num_scoring_users": 1900000,
+                    "num_favorites": 78000,
+                    "statistics": {
+                        "status": {
+                            "watching": "90000",
+                            "completed": "2300000",
+                            "on_hold": "80000",
+                            "dropped": "100000",
+                            "plan_to_watch": "330000"
+                        },
+                        "num_list_users": 2900000
+                    },
+                    "status": "finished_airing",
+                    "start_date": "2002-10-03",
+                    "num_episodes": 220
+                }))),
+                _ => Err(StatusCode::NOT_FOUND),
+            }
+        }
+
+        async fn manga(Path(id): Path<u64>) -> Result<Json<serde_json::Value>, StatusCode> {
+            match id {
+                13 => Ok(Json(serde_json::json!({
+                    "id": 13,
+                    "title": "One Piece",
+                    "mean": 9.22,
+                    "popularity": 3,
+                    "num_list_users": 720000,
+                    "rank": 3,
+                    "num_scoring_users": 410000,
+                    "status": "currently_publishing",
+                    "start_date": "1997-07-22",
+                    "num_volumes": 0,
+                    "num_chapters": 0
+                }))),
+                121496 => Ok(Json(serde_json::json!({
+                    "id": 121496,
+                    "title": "Na Honjaman Level Up",
+                    "media_type": "manhwa",
+                    "mean": 8.66,
+                    "popularity": 83,
+                    "num_list_users": 240000,
+                    "rank": 30,
+                    "num_scoring_users": 120000,
+                    "status": "finished",
+                    "start_date": "2018-03-04",
+                    "num_volumes": 0,
+                    "num_chapters": 201
+                }))),
+                _ => Err(StatusCode::NOT_FOUND),
+            }
+        }
+
+        let router = Router::new()
+            .route("/anime", get(search))
+            .route("/anime/:id", get(anime))
+            .route("/manga/:id", get(manga));
+        let state = mock_provider(router).await;
+
+        // No match and partially missing fields are never signed.
+        let by_name = |name: &str| MyAnimeRequest {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let result = fetch_metrics(
+            &state,
+            by_name("No Such Title"),
+            RequestBinding::default(),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+        let result =
+            fetch_metrics(&state, by_name("Unaired"), RequestBinding::default(), None).await;
+        assert!(matches!(result, Err(EnclaveError::IncompleteRecord(_))));
+
+        // Lookup by stable id.
+        let request = MyAnimeRequest {
+            mal_id: Some(20),
+            ..Default::default()
+        };
+        let signed = fetch_metrics(&state, request, RequestBinding::default(), None)
+            .await
+            .unwrap();
+        let data = &signed.response.data;
+        assert_eq!(data.mal_id, 20);
+        assert_eq!(data.title, "Naruto");
+        assert_eq!(data.external_average_rating, 798);
+        assert_eq!(data.match_confidence, MATCH_CONFIDENCE_SCALE);
+        assert_eq!(data.media_kind, MediaKind::Anime);
+        assert_eq!(
+            data.extended.list_status.as_ref().unwrap().completed,
+            2_300_000
+        );
+        assert_eq!(data.extended.num_episodes, Some(220));
+        let freshness = signed.freshness.unwrap();
+        assert_eq!(freshness.origin, CacheOrigin::Upstream);
+        assert_eq!(freshness.fetched_at_ms, signed.response.timestamp_ms);
+
+        // A repeat is served from cache with the original timestamp, unless
+        // the caller asks for fresher data.
+        let id_request = || MyAnimeRequest {
+            mal_id: Some(20),
+            ..Default::default()
+        };
+        let cached = fetch_metrics(&state, id_request(), RequestBinding::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(cached.freshness.unwrap().origin, CacheOrigin::Cache);
+        assert_eq!(cached.response.timestamp_ms, signed.response.timestamp_ms);
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let refetched = fetch_metrics(&state, id_request(), RequestBinding::default(), Some(0))
+            .await
+            .unwrap();
+        assert_eq!(refetched.freshness.unwrap().origin, CacheOrigin::Upstream);
+        assert!(refetched.response.timestamp_ms > signed.response.timestamp_ms);
+
+        // The same id names a different entity in each catalogue.
+        let request = MyAnimeRequest {
+            mal_id: Some(13),
+            media_kind: MediaKind::Manga,
+            ..Default::default()
+        };
+        let signed = fetch_metrics(&state, request, RequestBinding::default(), None)
+            .await
+            .unwrap();
+        let data = &signed.response.data;
+        assert_eq!(data.media_kind, MediaKind::Manga);
+        assert_eq!(data.title, "One Piece");
+        assert_eq!(data.external_average_rating, 922);
+        assert_eq!(data.extended.list_status, None);
+        assert_eq!(data.extended.num_chapters, None);
+        let request = MyAnimeRequest {
+            mal_id: Some(13),
+            ..Default::default()
+        };
+        let result = fetch_metrics(&state, request, RequestBinding::default(), None).await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+
+        // Manhwa lives in the manga catalogue but is signed as its own category.
+        for media_kind in [MediaKind::Manga, MediaKind::Manhwa] {
+            let request = MyAnimeRequest {
+                mal_id: Some(121496),
+                media_kind,
+                ..Default::default()
+            };
+            let signed = fetch_metrics(&state, request, RequestBinding::default(), None)
+                .await
+                .unwrap();
+            assert_eq!(signed.response.data.media_kind, MediaKind::Manhwa);
+            assert_eq!(signed.response.data.extended.num_chapters, Some(201));
+        }
+
+        let request = MyAnimeRequest {
+            mal_id: Some(404),
+            ..Default::default()
+        };
+        let result = fetch_metrics(&state, request, RequestBinding::default(), None).await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+
+        let result = fetch_metrics(
+            &state,
+            MyAnimeRequest::default(),
+            RequestBinding::default(),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(EnclaveError::InvalidInput(_))));
+    }
+
+    fn naruto_extended() -> MalExtendedMetrics {
+        MalExtendedMetrics {
+            version: EXTENDED_METRICS_VERSION,
+            rank: Some(660),
+            num_scoring_users: 1_900_000,
+            num_favorites: Some(78_000),
+            list_status: Some(ListStatusCounts {
+                watching: 90_000,
+                completed: 2_300_000,
+                on_hold: 80_000,
+                dropped: 100_000,
+                plan_to_watch: 330_000,
+            }),
+            status: "finished_airing".to_string(),
+            start_date: Some("2002-10-03".to_string()),
+            num_episodes: Some(220),
+            num_volumes: None,
+            num_chapters: None,
+        }
+    }
+
+    #[test]
+    fn test_extended_metrics_optional_fields() {
+        let node = serde_json::json!({
+            "num_scoring_users": 0,
+            "statistics": { "status": {
+                "watching": "5", "completed": "0", "on_hold": "0", "dropped": "0", "plan_to_watch": "12"
+            } },
+            "status": "currently_airing",
+            "num_episodes": 0
+        });
+        let extended = extended_metrics(&node, MediaKind::Anime).unwrap();
+        assert_eq!(extended.rank, None);
+        assert_eq!(extended.num_episodes, None);
+        assert_eq!(extended.list_status.unwrap().plan_to_watch, 12);
+
+        let mut missing = node.clone();
+        missing["statistics"]["status"]
+            .as_object_mut()
+            .unwrap()
+            .remove("dropped");
+        let result = extended_metrics(&missing, MediaKind::Anime);
+        assert!(matches!(result, Err(EnclaveError::IncompleteRecord(_))));
+        // Manga never carries a status breakdown.
+        assert_eq!(
+            extended_metrics(&missing, MediaKind::Manga)
+                .unwrap()
+                .list_status,
+            None
+        );
+    }
+
+    #[test]
+    fn test_media_kind_matches_odx_categories() {
+        // odx::datatypes CATEGORY_ANIME, CATEGORY_MANGA and CATEGORY_MANHWA
+        assert_eq!(bcs::to_bytes(&MediaKind::Anime).unwrap(), [0]);
+        assert_eq!(bcs::to_bytes(&MediaKind::Manga).unwrap(), [1]);
+        assert_eq!(bcs::to_bytes(&MediaKind::Manhwa).unwrap(), [2]);
+
+        let request: MyAnimeRequest =
+            serde_json::from_str(r#"{"name":"Solo Leveling","media_kind":"manhwa"}"#).unwrap();
+        assert_eq!(request.media_kind.as_str(), "manga");
+        assert_eq!(request.media_type_filter(), Some("manhwa"));
+
+        let manhwa = serde_json::json!({ "media_type": "manhwa" });
+        let manga = serde_json::json!({ "media_type": "manga" });
+        assert_eq!(MediaKind::Manga.of_node(&manhwa), MediaKind::Manhwa);
+        assert_eq!(MediaKind::Manhwa.of_node(&manga), MediaKind::Manga);
+        assert_eq!(MediaKind::Anime.of_node(&manhwa), MediaKind::Anime);
+    }
+
+    // Test vector shared with smartcontract/odx/tests/nautilus_payload_tests.move.
+    // Signed with the ed25519 key whose seed is the bytes 0x00..=0x1f.
+    const VECTOR_PUBLIC_KEY: &str =
+        "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+    const VECTOR_PAYLOAD: &str = "000068e5cf8b01000006010b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b012a00000000000000001400000000000000064e617275746f1e03000000000000080000000000000020402c0000000000066e617275746f102700000000000002019402000000000000e0fd1c000000000001b03001000000000001905f01000000000060182300000000008038010000000000a08601000000000010090500000000000f66696e69736865645f616972696e67010a323030322d31302d303301dc000000000000000000";
+    const VECTOR_SIGNATURE: &str = "af48c66ccc425f72404f166d0b5392107e163124578dc37702d84188e2ee6db4c8353cafbad412469200e57da6940f7b076a41bec8c79083895d83a661f21c07";
+
+    #[test]
+    fn test_bcs_vector() {
+        let seed: Vec<u8> = (0u8..32).collect();
+        let kp = Ed25519KeyPair::from(Ed25519PrivateKey::from_bytes(&seed).unwrap());
+        let metrics = MyMetrics {
+            version: METRICS_PAYLOAD_VERSION,
+            binding: RequestBinding {
+                ip_token_id: Some(ObjectId([0x0b; 32])),
+                nonce: Some(42),
+            },
+            media_kind: MediaKind::Anime,
+            mal_id: 20,
+            title: "Naruto".to_string(),
+            external_average_rating: to_fixed_point(7.98, RATING_SCALE),
+            external_popularity_rank: 8,
+            external_member_count: 2_900_000,
+            queried_name: "naruto".to_string(),
+            match_confidence: to_fixed_point(1.0, MATCH_CONFIDENCE_SCALE),
+            extended: naruto_extended(),
+        };
+        let signed = to_signed_response(&kp, metrics, 1_700_000_000_000, IntentScope::MyAnimeList);
+
+        assert_eq!(Hex::encode(kp.public().as_bytes()), VECTOR_PUBLIC_KEY);
+        assert_eq!(
+            Hex::encode(bcs::to_bytes(&signed.response).unwrap()),
+            VECTOR_PAYLOAD
+        );
+        assert_eq!(signed.signature, VECTOR_SIGNATURE);
     }
 }
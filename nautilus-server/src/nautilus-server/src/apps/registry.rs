@@ -0,0 +1,207 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common::{IntentMessage, ProcessDataRequest, ProcessedDataResponse};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::extract::State;
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[cfg(feature = "anilist")]
+use super::anilist::{self, AniListMetrics, AniListRequest};
+#[cfg(feature = "mangadex")]
+use super::mangadex::{self, MangaDexMetrics, MangaDexRequest};
+#[cfg(feature = "myanimelist")]
+use super::myanimelist::{self, MyAnimeRequest, MyMetrics};
+
+/// Upstream data sources that `process_data` can route to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    MyAnimeList,
+    AniList,
+    MangaDex,
+}
+
+impl Source {
+    /// Every source known to the registry, whether or not it is compiled in.
+    pub const ALL: [Source; 3] = [Source::MyAnimeList, Source::AniList, Source::MangaDex];
+
+    /// Name used for this source in requests, e.g. `"myanimelist"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::MyAnimeList => "myanimelist",
+            Source::AniList => "anilist",
+            Source::MangaDex => "mangadex",
+        }
+    }
+
+    /// Whether the provider for this source is compiled into this build.
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Source::MyAnimeList => cfg!(feature = "myanimelist"),
+            Source::AniList => cfg!(feature = "anilist"),
+            Source::MangaDex => cfg!(feature = "mangadex"),
+        }
+    }
+
+    /// Sources whose providers are compiled into this build.
+    pub fn enabled() -> Vec<Source> {
+        Self::ALL.into_iter().filter(|s| s.is_enabled()).collect()
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Source {
+    type Err = EnclaveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|source| source.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| EnclaveError::UnsupportedSource(s.to_string()))
+    }
+}
+
+/// Request payload for `process_data`. `source` selects the provider and the
+/// remaining fields are parsed into that provider's own request type.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SourceRequest {
+    #[serde(default = "default_source")]
+    pub source: String,
+    #[serde(flatten)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_source() -> String {
+    Source::MyAnimeList.as_str().to_string()
+}
+
+/// Signed response of any provider. Serialized untagged so every source
+/// returns the same `{ response: IntentMessage, signature }` envelope.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SignedMetrics {
+    #[cfg(feature = "myanimelist")]
+    MyAnimeList(ProcessedDataResponse<IntentMessage<MyMetrics>>),
+    #[cfg(feature = "anilist")]
+    AniList(ProcessedDataResponse<IntentMessage<AniListMetrics>>),
+    #[cfg(feature = "mangadex")]
+    MangaDex(ProcessedDataResponse<IntentMessage<MangaDexMetrics>>),
+}
+
+pub async fn process_data(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ProcessDataRequest<SourceRequest>>,
+) -> Result<Json<SignedMetrics>, EnclaveError> {
+    let binding = request.binding();
+    let max_age_ms = request.max_age_ms;
+    let SourceRequest { source, params } = request.payload;
+    let source: Source = source.parse()?;
+
+    let signed = match source {
+        #[cfg(feature = "myanimelist")]
+        Source::MyAnimeList => {
+            let req: MyAnimeRequest = parse_params(params)?;
+            SignedMetrics::MyAnimeList(
+                myanimelist::fetch_metrics(&state, req, binding, max_age_ms).await?,
+            )
+        }
+        #[cfg(feature = "anilist")]
+        Source::AniList => {
+            let req: AniListRequest = parse_params(params)?;
+            SignedMetrics::AniList(anilist::fetch_metrics(&state, req, binding, max_age_ms).await?)
+        }
+        #[cfg(feature = "mangadex")]
+        Source::MangaDex => {
+            let req: MangaDexRequest = parse_params(params)?;
+            SignedMetrics::MangaDex(
+                mangadex::fetch_metrics(&state, req, binding, max_age_ms).await?,
+            )
+        }
+        #[cfg(not(all(feature = "myanimelist", feature = "anilist", feature = "mangadex")))]
+        _ => return Err(EnclaveError::UnsupportedSource(source.to_string())),
+    };
+
+    Ok(Json(signed))
+}
+
+/// Parse the provider-specific fields of a request.
+fn parse_params<T: DeserializeOwned>(
+    params: serde_json::Map<String, serde_json::Value>,
+) -> Result<T, EnclaveError> {
+    serde_json::from_value(serde_json::Value::Object(params))
+        .map_err(|e| EnclaveError::InvalidInput(format!("invalid request payload: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::RequestBinding;
+
+    #[test]
+    fn test_source_parsing() {
+        assert_eq!(
+            "myanimelist".parse::<Source>().unwrap(),
+            Source::MyAnimeList
+        );
+        assert_eq!(
+            " MyAnimeList ".parse::<Source>().unwrap(),
+            Source::MyAnimeList
+        );
+        assert_eq!("anilist".parse::<Source>().unwrap(), Source::AniList);
+        assert_eq!("mangadex".parse::<Source>().unwrap(), Source::MangaDex);
+        assert!(matches!(
+            "kitsu".parse::<Source>(),
+            Err(EnclaveError::UnsupportedSource(s)) if s == "kitsu"
+        ));
+    }
+
+    #[test]
+    fn test_request_binding() {
+        let req: ProcessDataRequest<SourceRequest> =
+            serde_json::from_str(r#"{"payload":{"name":"Naruto"},"ip_token_id":"0x2","nonce":7}"#)
+                .unwrap();
+        let binding = req.binding();
+        let mut expected = [0u8; 32];
+        expected[31] = 2;
+        assert_eq!(binding.ip_token_id.unwrap().0, expected);
+        assert_eq!(binding.nonce, Some(7));
+
+        let req: ProcessDataRequest<SourceRequest> =
+            serde_json::from_str(r#"{"payload":{"name":"Naruto"}}"#).unwrap();
+        assert_eq!(req.binding(), RequestBinding::default());
+
+        // Nonces above 2^53 arrive as strings from JavaScript clients
+        let req: ProcessDataRequest<SourceRequest> =
+            serde_json::from_str(r#"{"payload":{"name":"Naruto"},"nonce":"18446744073709551615"}"#)
+                .unwrap();
+        assert_eq!(req.binding().nonce, Some(u64::MAX));
+        assert!(serde_json::from_str::<ProcessDataRequest<SourceRequest>>(
+            r#"{"payload":{"name":"Naruto"},"nonce":"-1"}"#,
+        )
+        .is_err());
+
+        assert!(serde_json::from_str::<ProcessDataRequest<SourceRequest>>(
+            r#"{"payload":{"name":"Naruto"},"ip_token_id":"0xnothex"}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_source_request_defaults_to_myanimelist() {
+        let req: SourceRequest =
+            serde_json::from_str(r#"{"name":"Naruto","ip_token_id":"0x1"}"#).unwrap();
+        assert_eq!(req.source, "myanimelist");
+        assert_eq!(req.params.get("name").unwrap(), "Naruto");
+    }
+}
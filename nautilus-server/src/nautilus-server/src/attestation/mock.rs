@@ -0,0 +1,172 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mock Nitro Secure Module for running the server outside an enclave.
+//! Documents are structurally identical to real ones but are signed by a CA
+//! generated at boot, so they prove nothing about the code that is running.
+
+use super::{
+    cose_protected_header, cose_sig_structure, encode_cose_sign1, AttestationDocument,
+    MockRootCertificateResponse,
+};
+use axum::Json;
+use fastcrypto::encoding::{Encoding, Hex};
+use lazy_static::lazy_static;
+use p384::ecdsa::signature::Signer;
+use p384::ecdsa::{Signature, SigningKey};
+use p384::pkcs8::DecodePrivateKey;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose,
+    PKCS_ECDSA_P384_SHA384,
+};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of PCRs reported by the NSM.
+const PCR_COUNT: usize = 16;
+
+/// Length of a SHA-384 PCR value.
+const PCR_LEN: usize = 48;
+
+lazy_static! {
+    /// Mock NSM shared by the process, generated on first use.
+    pub static ref MOCK_NSM: MockNsm = MockNsm::generate().expect("mock NSM generation should not fail");
+}
+
+pub struct MockNsm {
+    root_certificate: Vec<u8>,
+    certificate: Vec<u8>,
+    signing_key: SigningKey,
+    pcrs: BTreeMap<usize, Vec<u8>>,
+}
+
+impl MockNsm {
+    /// Generate a root CA and a leaf certificate it issues. PCRs are zero,
+    /// like a debug-mode enclave, unless overridden with `MOCK_PCR<n>` hex.
+    pub fn generate() -> anyhow::Result<Self> {
+        let root_key = KeyPair::generate_for(&PKCS_ECDSA_P384_SHA384)?;
+        let mut root_params = CertificateParams::default();
+        root_params
+            .distinguished_name
+            .push(DnType::CommonName, "mock.aws.nitro-enclaves");
+        root_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        root_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let root = root_params.self_signed(&root_key)?;
+
+        let leaf_key = KeyPair::generate_for(&PKCS_ECDSA_P384_SHA384)?;
+        let mut leaf_params = CertificateParams::default();
+        leaf_params.distinguished_name.push(
+            DnType::CommonName,
+            "i-mock-enc00000000000000.mock.aws.nitro-enclaves",
+        );
+        leaf_params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        let leaf = leaf_params.signed_by(&leaf_key, &root, &root_key)?;
+
+        let signing_key = SigningKey::from_pkcs8_der(leaf_key.serialized_der())
+            .map_err(|e| anyhow::anyhow!("failed to load mock signing key: {e}"))?;
+
+        let mut pcrs = BTreeMap::new();
+        for index in 0..PCR_COUNT {
+            let value = match std::env::var(format!("MOCK_PCR{index}")) {
+                Ok(hex) => Hex::decode(hex.trim().trim_start_matches("0x"))
+                    .map_err(|e| anyhow::anyhow!("invalid MOCK_PCR{index}: {e}"))?,
+                Err(_) => vec![0u8; PCR_LEN],
+            };
+            anyhow::ensure!(
+                value.len() == PCR_LEN,
+                "MOCK_PCR{index} must be {PCR_LEN} bytes"
+            );
+            pcrs.insert(index, value);
+        }
+
+        Ok(Self {
+            root_certificate: root.der().to_vec(),
+            certificate: leaf.der().to_vec(),
+            signing_key,
+            pcrs,
+        })
+    }
+
+    /// DER encoded root certificate that verifiers should trust in dev mode.
+    pub fn root_certificate(&self) -> &[u8] {
+        &self.root_certificate
+    }
+
+    /// Produce a COSE_Sign1 attestation document, like
+    /// `NsmRequest::Attestation` does on Nitro hardware.
+    pub fn attest(
+        &self,
+        public_key: Option<Vec<u8>>,
+        user_data: Option<Vec<u8>>,
+        nonce: Option<Vec<u8>>,
+    ) -> Vec<u8> {
+        let document = AttestationDocument {
+            module_id: "i-mock-enc00000000000000".to_string(),
+            digest: "SHA384".to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            pcrs: self
+                .pcrs
+                .iter()
+                .map(|(index, value)| (*index, ByteBuf::from(value.clone())))
+                .collect(),
+            certificate: ByteBuf::from(self.certificate.clone()),
+            cabundle: vec![ByteBuf::from(self.root_certificate.clone())],
+            public_key: public_key.map(ByteBuf::from),
+            user_data: user_data.map(ByteBuf::from),
+            nonce: nonce.map(ByteBuf::from),
+        };
+
+        let protected = cose_protected_header();
+        let payload = serde_cbor::to_vec(&document).expect("should not fail");
+        let signature: Signature = self
+            .signing_key
+            .sign(&cose_sig_structure(&protected, &payload));
+        encode_cose_sign1(&protected, &payload, &signature.to_bytes())
+    }
+}
+
+/// Endpoint that returns the mock CA root, to be passed to verifiers in
+/// place of the AWS Nitro root certificate.
+pub async fn get_mock_root_certificate() -> Json<MockRootCertificateResponse> {
+    Json(MockRootCertificateResponse {
+        root_certificate: Hex::encode(MOCK_NSM.root_certificate()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::ecdsa::signature::Verifier;
+
+    #[test]
+    fn test_mock_attestation_structure() {
+        let nsm = MockNsm::generate().unwrap();
+        let public_key = vec![7u8; 32];
+        let cose = nsm.attest(Some(public_key.clone()), None, None);
+
+        let (protected, _unprotected, payload, signature): (
+            ByteBuf,
+            BTreeMap<i64, i64>,
+            ByteBuf,
+            ByteBuf,
+        ) = serde_cbor::from_slice(&cose).unwrap();
+        assert_eq!(protected.to_vec(), cose_protected_header());
+
+        let document: AttestationDocument = serde_cbor::from_slice(&payload).unwrap();
+        assert_eq!(document.digest, "SHA384");
+        assert_eq!(document.pcrs.len(), PCR_COUNT);
+        assert_eq!(document.public_key.unwrap().to_vec(), public_key);
+        assert_eq!(document.cabundle[0].to_vec(), nsm.root_certificate());
+        assert!(document.nonce.is_none());
+
+        let signature = Signature::from_slice(&signature).unwrap();
+        nsm.signing_key
+            .verifying_key()
+            .verify(&cose_sig_structure(&protected, &payload), &signature)
+            .unwrap();
+    }
+}
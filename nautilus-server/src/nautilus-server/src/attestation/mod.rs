@@ -0,0 +1,70 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+#[cfg(feature = "dev-attestation")]
+pub mod mock;
+pub mod verify;
+
+/// COSE algorithm id for ECDSA with SHA-384, the only algorithm Nitro uses.
+pub const COSE_ALG_ES384: i64 = -35;
+
+/// COSE header label for the algorithm.
+const COSE_HEADER_ALG: i64 = 1;
+
+/// Attestation document as produced by the Nitro Secure Module, i.e. the
+/// CBOR payload of the COSE_Sign1 structure returned by `get_attestation`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationDocument {
+    pub module_id: String,
+    /// Digest used for the PCRs, always "SHA384" on Nitro.
+    pub digest: String,
+    pub timestamp: u64,
+    pub pcrs: BTreeMap<usize, ByteBuf>,
+    /// DER encoded certificate that signed this document.
+    pub certificate: ByteBuf,
+    /// DER encoded issuer chain, root first.
+    pub cabundle: Vec<ByteBuf>,
+    pub public_key: Option<ByteBuf>,
+    pub user_data: Option<ByteBuf>,
+    pub nonce: Option<ByteBuf>,
+}
+
+/// Response of `/mock_root_certificate` in dev builds. `verify-attestation
+/// --root` accepts it as saved.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MockRootCertificateResponse {
+    /// DER encoded root certificate serialized in Hex.
+    pub root_certificate: String,
+}
+
+/// Serialized protected header `{ alg: ES384 }`.
+pub fn cose_protected_header() -> Vec<u8> {
+    let header = BTreeMap::from([(COSE_HEADER_ALG, COSE_ALG_ES384)]);
+    serde_cbor::to_vec(&header).expect("should not fail")
+}
+
+/// Bytes covered by a COSE_Sign1 signature (RFC 8152, Sig_structure).
+pub fn cose_sig_structure(protected: &[u8], payload: &[u8]) -> Vec<u8> {
+    let sig_structure = (
+        "Signature1",
+        ByteBuf::from(protected.to_vec()),
+        ByteBuf::new(),
+        ByteBuf::from(payload.to_vec()),
+    );
+    serde_cbor::to_vec(&sig_structure).expect("should not fail")
+}
+
+/// Encode an untagged COSE_Sign1 array, the form the NSM returns.
+pub fn encode_cose_sign1(protected: &[u8], payload: &[u8], signature: &[u8]) -> Vec<u8> {
+    let cose_sign1 = (
+        ByteBuf::from(protected.to_vec()),
+        BTreeMap::<i64, i64>::new(),
+        ByteBuf::from(payload.to_vec()),
+        ByteBuf::from(signature.to_vec()),
+    );
+    serde_cbor::to_vec(&cose_sign1).expect("should not fail")
+}
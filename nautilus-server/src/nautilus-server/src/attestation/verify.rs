@@ -0,0 +1,245 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offline verification of attestation documents returned by `get_attestation`.
+
+use super::{cose_sig_structure, AttestationDocument, MockRootCertificateResponse, COSE_ALG_ES384};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fastcrypto::encoding::{Encoding, Hex};
+use p384::ecdsa::signature::Verifier;
+use p384::ecdsa::{Signature, VerifyingKey};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::pem::parse_x509_pem;
+use x509_parser::prelude::{ASN1Time, FromDer};
+
+/// Highest PCR index extracted from a verified document.
+pub const MAX_PCR_INDEX: usize = 8;
+
+/// Fields of an attestation document whose signature and certificate chain
+/// have been verified. Byte fields are hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VerifiedAttestation {
+    pub module_id: String,
+    pub timestamp: u64,
+    /// PCR0 to PCR8.
+    pub pcrs: BTreeMap<usize, String>,
+    pub public_key: Option<String>,
+    pub user_data: Option<String>,
+    pub nonce: Option<String>,
+}
+
+/// Verify a hex encoded COSE_Sign1 attestation document against a trusted
+/// root certificate (DER, PEM, hex, or the JSON returned by
+/// `/mock_root_certificate`): the cabundle must start with the root,
+/// every certificate must be signed by its issuer and valid at the document
+/// timestamp, and the COSE signature must verify under the leaf key.
+pub fn verify_attestation(document_hex: &str, trusted_root: &[u8]) -> Result<VerifiedAttestation> {
+    let cose = Hex::decode(document_hex.trim().trim_start_matches("0x"))
+        .map_err(|e| anyhow!("attestation is not valid hex: {e}"))?;
+    let (protected, _unprotected, payload, signature): (
+        ByteBuf,
+        serde_cbor::Value,
+        ByteBuf,
+        ByteBuf,
+    ) = serde_cbor::from_slice(&cose).context("attestation is not a COSE_Sign1 structure")?;
+
+    let header: BTreeMap<i64, serde_cbor::Value> =
+        serde_cbor::from_slice(&protected).context("invalid COSE protected header")?;
+    ensure!(
+        header.get(&1) == Some(&serde_cbor::Value::Integer(COSE_ALG_ES384.into())),
+        "unsupported COSE algorithm, expected ES384"
+    );
+
+    let document: AttestationDocument =
+        serde_cbor::from_slice(&payload).context("invalid attestation document")?;
+    ensure!(
+        document.digest == "SHA384",
+        "unsupported PCR digest {}",
+        document.digest
+    );
+
+    // Certificate chain: root, intermediates..., leaf.
+    let root_der = root_certificate_der(trusted_root)?;
+    let first = document
+        .cabundle
+        .first()
+        .ok_or_else(|| anyhow!("attestation has an empty cabundle"))?;
+    ensure!(
+        first.as_slice() == root_der.as_slice(),
+        "cabundle does not start with the trusted root"
+    );
+
+    let valid_at = ASN1Time::from_timestamp((document.timestamp / 1000) as i64)
+        .map_err(|e| anyhow!("invalid attestation timestamp: {e}"))?;
+    let mut chain = Vec::with_capacity(document.cabundle.len() + 1);
+    for der in document
+        .cabundle
+        .iter()
+        .chain(std::iter::once(&document.certificate))
+    {
+        let (_, cert) =
+            X509Certificate::from_der(der).map_err(|e| anyhow!("invalid certificate: {e}"))?;
+        chain.push(cert);
+    }
+    for (i, cert) in chain.iter().enumerate() {
+        ensure!(
+            cert.validity().is_valid_at(valid_at),
+            "certificate {} is not valid at the attestation timestamp",
+            cert.subject()
+        );
+        let issuer = if i == 0 { cert } else { &chain[i - 1] };
+        if i + 1 < chain.len() {
+            ensure!(
+                cert.is_ca(),
+                "issuer certificate {} is not a CA",
+                cert.subject()
+            );
+        }
+        cert.verify_signature(Some(issuer.public_key()))
+            .map_err(|e| {
+                anyhow!(
+                    "certificate {} has an invalid signature: {e}",
+                    cert.subject()
+                )
+            })?;
+    }
+
+    // COSE signature under the leaf certificate key.
+    let leaf = chain.last().expect("chain contains the leaf");
+    let leaf_key =
+        VerifyingKey::from_sec1_bytes(leaf.public_key().subject_public_key.data.as_ref())
+            .map_err(|e| anyhow!("leaf certificate key is not P-384: {e}"))?;
+    let signature =
+        Signature::from_slice(&signature).map_err(|e| anyhow!("invalid COSE signature: {e}"))?;
+    leaf_key
+        .verify(&cose_sig_structure(&protected, &payload), &signature)
+        .map_err(|_| anyhow!("COSE signature does not verify under the leaf certificate"))?;
+
+    Ok(VerifiedAttestation {
+        module_id: document.module_id,
+        timestamp: document.timestamp,
+        pcrs: document
+            .pcrs
+            .into_iter()
+            .filter(|(index, _)| *index <= MAX_PCR_INDEX)
+            .map(|(index, value)| (index, Hex::encode(value)))
+            .collect(),
+        public_key: document.public_key.map(Hex::encode),
+        user_data: document.user_data.map(Hex::encode),
+        nonce: document.nonce.map(Hex::encode),
+    })
+}
+
+/// Check verified PCRs against expected hex values, e.g. from `out/nitro.pcrs`.
+pub fn check_pcrs(
+    verified: &VerifiedAttestation,
+    expected: &BTreeMap<usize, String>,
+) -> Result<()> {
+    for (index, want) in expected {
+        let want = want.trim().trim_start_matches("0x").to_lowercase();
+        match verified.pcrs.get(index) {
+            Some(got) if *got == want => {}
+            Some(got) => bail!("PCR{index} mismatch: expected {want}, got {got}"),
+            None => bail!("PCR{index} missing from attestation"),
+        }
+    }
+    Ok(())
+}
+
+/// Parse expected PCRs from `out/nitro.pcrs`, one `<hex> PCR<n>` per line.
+pub fn parse_nitro_pcrs(contents: &str) -> Result<BTreeMap<usize, String>> {
+    let mut pcrs = BTreeMap::new();
+    for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let mut parts = line.split_whitespace();
+        let (Some(value), Some(name)) = (parts.next(), parts.next()) else {
+            bail!("invalid PCR line: {line}");
+        };
+        let index = name
+            .strip_prefix("PCR")
+            .and_then(|i| i.parse().ok())
+            .ok_or_else(|| anyhow!("invalid PCR name: {name}"))?;
+        pcrs.insert(index, value.to_string());
+    }
+    Ok(pcrs)
+}
+
+/// Accept the trusted root as DER, PEM, hex, or the `/mock_root_certificate`
+/// JSON body.
+fn root_certificate_der(root: &[u8]) -> Result<Vec<u8>> {
+    if root.starts_with(b"-----BEGIN") {
+        let (_, pem) =
+            parse_x509_pem(root).map_err(|e| anyhow!("invalid root certificate PEM: {e}"))?;
+        return Ok(pem.contents);
+    }
+    // DER starts with a SEQUENCE header that is never valid UTF-8 text.
+    let Ok(text) = std::str::from_utf8(root) else {
+        return Ok(root.to_vec());
+    };
+    let hex = match serde_json::from_str::<MockRootCertificateResponse>(text) {
+        Ok(response) => response.root_certificate,
+        Err(_) => text.to_string(),
+    };
+    Hex::decode(hex.trim().trim_start_matches("0x"))
+        .map_err(|e| anyhow!("root certificate is not DER, PEM or hex: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "dev-attestation")]
+    use crate::attestation::mock::MockNsm;
+
+    #[test]
+    fn test_parse_nitro_pcrs() {
+        let pcrs = parse_nitro_pcrs("aa11 PCR0\nbb22 PCR1\n\ncc33 PCR2\n").unwrap();
+        assert_eq!(pcrs.len(), 3);
+        assert_eq!(pcrs[&2], "cc33");
+        assert!(parse_nitro_pcrs("aa11 PCRX").is_err());
+    }
+
+    #[cfg(feature = "dev-attestation")]
+    #[test]
+    fn test_verify_mock_attestation() {
+        let nsm = MockNsm::generate().unwrap();
+        let document =
+            Hex::encode(nsm.attest(Some(vec![1u8; 32]), Some(vec![2u8; 4]), Some(vec![3u8; 8])));
+
+        let verified = verify_attestation(&document, nsm.root_certificate()).unwrap();
+        assert_eq!(verified.pcrs.len(), MAX_PCR_INDEX + 1);
+        assert_eq!(verified.public_key, Some(Hex::encode([1u8; 32])));
+        assert_eq!(verified.user_data, Some(Hex::encode([2u8; 4])));
+        assert_eq!(verified.nonce, Some(Hex::encode([3u8; 8])));
+
+        let zeros = BTreeMap::from([(0, Hex::encode([0u8; 48]))]);
+        check_pcrs(&verified, &zeros).unwrap();
+        let other = BTreeMap::from([(0, Hex::encode([1u8; 48]))]);
+        assert!(check_pcrs(&verified, &other).is_err());
+    }
+
+    #[cfg(feature = "dev-attestation")]
+    #[tokio::test]
+    async fn test_verify_with_mock_root_response() {
+        use crate::attestation::mock::{get_mock_root_certificate, MOCK_NSM};
+
+        let document = Hex::encode(MOCK_NSM.attest(Some(vec![1u8; 32]), None, None));
+        let response = get_mock_root_certificate().await.0;
+
+        // As saved from the endpoint, and as the bare hex it carries
+        let json = serde_json::to_vec(&response).unwrap();
+        verify_attestation(&document, &json).unwrap();
+        verify_attestation(&document, response.root_certificate.as_bytes()).unwrap();
+        assert!(verify_attestation(&document, br#"{"root_certificate":"zz"}"#).is_err());
+    }
+
+    #[cfg(feature = "dev-attestation")]
+    #[test]
+    fn test_reject_untrusted_root() {
+        let nsm = MockNsm::generate().unwrap();
+        let other = MockNsm::generate().unwrap();
+        let document = Hex::encode(nsm.attest(Some(vec![1u8; 32]), None, None));
+        assert!(verify_attestation(&document, other.root_certificate()).is_err());
+    }
+}
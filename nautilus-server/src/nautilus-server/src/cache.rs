@@ -0,0 +1,609 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bounded LRU cache of unsigned provider metrics, owned by `AppState`.
+//! Entries are signed per request, so a cached value never carries a
+//! binding or signature from an earlier caller. Concurrent misses on the
+//! same key share a single upstream fetch, and keys that keep being hit are
+//! refreshed in the background shortly before they expire.
+
+use crate::app::Source;
+use crate::common::env_u64;
+use crate::EnclaveError;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+/// Default number of cached entries across all providers.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Default time a fetched value is served from cache.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Default period of the background sweep of expired entries.
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default window before expiry in which a hit on a hot key triggers a
+/// background refresh.
+pub const DEFAULT_REFRESH_AHEAD: Duration = Duration::from_secs(60);
+
+/// Default number of hits after which a cached key counts as hot.
+pub const DEFAULT_REFRESH_MIN_HITS: u64 = 3;
+
+/// Cache sizing and per-provider TTLs.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub capacity: usize,
+    /// TTL per provider, `DEFAULT_CACHE_TTL` for providers not listed.
+    pub ttls: HashMap<Source, Duration>,
+    pub sweep_interval: Duration,
+    /// Refresh hot keys this long before they expire. Zero disables refresh.
+    pub refresh_ahead: Duration,
+    /// Hits an entry needs before it is refreshed ahead of expiry.
+    pub refresh_min_hits: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CACHE_CAPACITY,
+            ttls: HashMap::new(),
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            refresh_ahead: DEFAULT_REFRESH_AHEAD,
+            refresh_min_hits: DEFAULT_REFRESH_MIN_HITS,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Read `CACHE_CAPACITY`, `CACHE_SWEEP_INTERVAL_SECS`,
+    /// `CACHE_REFRESH_AHEAD_SECS`, `CACHE_REFRESH_MIN_HITS` and
+    /// `CACHE_TTL_SECS_<SOURCE>` (e.g. `CACHE_TTL_SECS_MYANIMELIST`).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        if let Some(capacity) = env_u64("CACHE_CAPACITY")? {
+            config.capacity = capacity as usize;
+        }
+        if let Some(secs) = env_u64("CACHE_SWEEP_INTERVAL_SECS")? {
+            config.sweep_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_u64("CACHE_REFRESH_AHEAD_SECS")? {
+            config.refresh_ahead = Duration::from_secs(secs);
+        }
+        if let Some(hits) = env_u64("CACHE_REFRESH_MIN_HITS")? {
+            config.refresh_min_hits = hits;
+        }
+        for source in Source::ALL {
+            let var = format!("CACHE_TTL_SECS_{}", source.as_str().to_uppercase());
+            if let Some(secs) = env_u64(&var)? {
+                config.ttls.insert(source, Duration::from_secs(secs));
+            }
+        }
+        anyhow::ensure!(config.capacity > 0, "CACHE_CAPACITY must be positive");
+        anyhow::ensure!(
+            !config.sweep_interval.is_zero(),
+            "CACHE_SWEEP_INTERVAL_SECS must be positive"
+        );
+        Ok(config)
+    }
+
+    /// TTL applied to values fetched from `source`.
+    pub fn ttl(&self, source: Source) -> Duration {
+        self.ttls.get(&source).copied().unwrap_or(DEFAULT_CACHE_TTL)
+    }
+}
+
+/// Snapshot of the cache counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room for new ones.
+    pub evictions: u64,
+    /// Entries dropped because their TTL passed.
+    pub expirations: u64,
+    /// Misses served by waiting on another caller's in-flight fetch.
+    pub coalesced: u64,
+    /// Background refreshes of hot keys started.
+    pub refreshes: u64,
+}
+
+/// Where a served value came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheOrigin {
+    /// Fetched upstream for this request.
+    Upstream,
+    /// Fetched upstream by a concurrent request this one waited on.
+    Coalesced,
+    /// Served from cache.
+    Cache,
+}
+
+/// Unsigned freshness metadata returned next to a signature, so callers can
+/// tell how old the signed data is and whether it came from cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Freshness {
+    pub origin: CacheOrigin,
+    /// When the value was fetched upstream. This is the signed `timestamp_ms`.
+    pub fetched_at_ms: u64,
+    /// Age of the value when it was served.
+    pub age_ms: u64,
+}
+
+impl Freshness {
+    fn new(origin: CacheOrigin, fetched_at_ms: u64) -> Self {
+        Self {
+            origin,
+            fetched_at_ms,
+            age_ms: current_millis().saturating_sub(fetched_at_ms),
+        }
+    }
+}
+
+type Value = Arc<dyn Any + Send + Sync>;
+
+struct Entry {
+    fetched_at_ms: u64,
+    expires_at_ms: u64,
+    value: Value,
+    /// Hits since the value was fetched.
+    hits: u64,
+    /// A background refresh of this entry is running.
+    refreshing: bool,
+}
+
+/// Outcome of an upstream fetch, shared by every caller waiting on it.
+type InFlight = Arc<OnceCell<Result<(u64, Value), EnclaveError>>>;
+
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: Mutex<LruCache<String, Entry>>,
+    inflight: Mutex<HashMap<String, InFlight>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    coalesced: AtomicU64,
+    refreshes: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            inflight: Mutex::new(HashMap::new()),
+            config,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            refreshes: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the value cached under `key` and the time it was fetched, if it
+    /// has not expired.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self, key: &str) -> Option<(u64, T)> {
+        self.lookup(key, None, false)
+            .map(|(fetched_at_ms, value, _)| (fetched_at_ms, value))
+    }
+
+    /// Unexpired value for `key` fetched no more than `max_age_ms` ago. With
+    /// `claim_refresh`, also reports whether the caller should refresh the
+    /// entry in the background, marking it as refreshing if so.
+    fn lookup<T: Clone + Send + Sync + 'static>(
+        &self,
+        key: &str,
+        max_age_ms: Option<u64>,
+        claim_refresh: bool,
+    ) -> Option<(u64, T, bool)> {
+        let now = current_millis();
+        let refresh_ahead_ms = self.config.refresh_ahead.as_millis() as u64;
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let mut expired = false;
+        let found = match entries.get_mut(key) {
+            Some(entry) if now < entry.expires_at_ms => {
+                let too_old =
+                    max_age_ms.is_some_and(|max| now.saturating_sub(entry.fetched_at_ms) > max);
+                match entry.value.downcast_ref::<T>() {
+                    Some(value) if !too_old => {
+                        entry.hits += 1;
+                        let refresh = claim_refresh
+                            && refresh_ahead_ms > 0
+                            && !entry.refreshing
+                            && entry.hits >= self.config.refresh_min_hits
+                            && now.saturating_add(refresh_ahead_ms) >= entry.expires_at_ms;
+                        entry.refreshing |= refresh;
+                        Some((entry.fetched_at_ms, value.clone(), refresh))
+                    }
+                    _ => None,
+                }
+            }
+            Some(_) => {
+                expired = true;
+                None
+            }
+            None => None,
+        };
+        if expired {
+            entries.pop(key);
+            self.expirations.fetch_add(1, Ordering::Relaxed);
+        }
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Return the cached value for `key`, or run `fetch` and cache its result.
+    /// Cached values fetched more than `max_age_ms` ago are fetched again.
+    /// Concurrent callers missing on the same key wait for a single `fetch`
+    /// and all receive its result or error. If the fetching caller is
+    /// cancelled, one of the waiters takes over. A hit on a hot key close to
+    /// expiry is served from cache while `fetch` refreshes it in the
+    /// background.
+    pub async fn get_or_fetch<T, F, Fut>(
+        self: &Arc<Self>,
+        source: Source,
+        key: &str,
+        max_age_ms: Option<u64>,
+        fetch: F,
+    ) -> Result<(Freshness, T), EnclaveError>
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, EnclaveError>> + Send + 'static,
+    {
+        if let Some((fetched_at_ms, value, refresh)) = self.lookup::<T>(key, max_age_ms, true) {
+            if refresh {
+                self.spawn_refresh(source, key.to_string(), fetch);
+            }
+            return Ok((Freshness::new(CacheOrigin::Cache, fetched_at_ms), value));
+        }
+
+        let cell = {
+            let mut inflight = self.inflight.lock().expect("inflight lock poisoned");
+            match inflight.get(key) {
+                Some(cell) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    Arc::clone(cell)
+                }
+                None => {
+                    let cell = InFlight::default();
+                    inflight.insert(key.to_string(), Arc::clone(&cell));
+                    cell
+                }
+            }
+        };
+
+        let fetched_here = AtomicBool::new(false);
+        let result = cell
+            .get_or_init(|| async {
+                // A fetch for this key may have completed between the miss
+                // above and registering the in-flight entry.
+                if let Some((fetched_at_ms, value)) = self.peek(key, max_age_ms) {
+                    return Ok((fetched_at_ms, value));
+                }
+                fetched_here.store(true, Ordering::Relaxed);
+                let value = fetch().await?;
+                let fetched_at_ms = current_millis();
+                let value: Value = Arc::new(value);
+                self.insert_value(source, key.to_string(), fetched_at_ms, Arc::clone(&value));
+                Ok((fetched_at_ms, value))
+            })
+            .await;
+
+        {
+            let mut inflight = self.inflight.lock().expect("inflight lock poisoned");
+            if inflight.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+                inflight.remove(key);
+            }
+        }
+
+        let (fetched_at_ms, value) = result.clone()?;
+        let value = value
+            .downcast_ref::<T>()
+            .cloned()
+            .ok_or_else(|| EnclaveError::Internal(format!("cache type mismatch for {key}")))?;
+        let origin = if fetched_here.load(Ordering::Relaxed) {
+            CacheOrigin::Upstream
+        } else {
+            CacheOrigin::Coalesced
+        };
+        Ok((Freshness::new(origin, fetched_at_ms), value))
+    }
+
+    /// Unexpired value for `key` fetched no more than `max_age_ms` ago,
+    /// without touching the counters.
+    fn peek(&self, key: &str, max_age_ms: Option<u64>) -> Option<(u64, Value)> {
+        let now = current_millis();
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        entries
+            .peek(key)
+            .filter(|entry| now < entry.expires_at_ms)
+            .filter(|entry| {
+                max_age_ms.is_none_or(|max| now.saturating_sub(entry.fetched_at_ms) <= max)
+            })
+            .map(|entry| (entry.fetched_at_ms, Arc::clone(&entry.value)))
+    }
+
+    /// Refetch `key` in the background, replacing the entry on success.
+    fn spawn_refresh<T, F, Fut>(self: &Arc<Self>, source: Source, key: String, fetch: F)
+    where
+        T: Send + Sync + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, EnclaveError>> + Send + 'static,
+    {
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            match fetch().await {
+                Ok(value) => cache.insert_value(source, key, current_millis(), Arc::new(value)),
+                Err(e) => {
+                    debug!("background refresh of {} failed: {:?}", key, e);
+                    let mut entries = cache.entries.lock().expect("cache lock poisoned");
+                    if let Some(entry) = entries.peek_mut(&key) {
+                        entry.refreshing = false;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Cache `value` fetched from `source` at `fetched_at_ms`.
+    pub fn insert<T: Send + Sync + 'static>(
+        &self,
+        source: Source,
+        key: String,
+        fetched_at_ms: u64,
+        value: T,
+    ) {
+        self.insert_value(source, key, fetched_at_ms, Arc::new(value));
+    }
+
+    fn insert_value(&self, source: Source, key: String, fetched_at_ms: u64, value: Value) {
+        let ttl_ms = self.config.ttl(source).as_millis() as u64;
+        let entry = Entry {
+            fetched_at_ms,
+            expires_at_ms: fetched_at_ms.saturating_add(ttl_ms),
+            value,
+            hits: 0,
+            refreshing: false,
+        };
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        if let Some((evicted, _)) = entries.push(key.clone(), entry) {
+            if evicted != key {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Drop every expired entry, returning how many were removed.
+    pub fn evict_expired(&self) -> usize {
+        let now = current_millis();
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let expired: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| now >= entry.expires_at_ms)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            entries.pop(key);
+        }
+        self.expirations
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        expired.len()
+    }
+
+    /// Evict expired entries every `sweep_interval` for as long as the
+    /// process runs.
+    pub fn spawn_sweeper(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cache.config.sweep_interval);
+            loop {
+                interval.tick().await;
+                let removed = cache.evict_expired();
+                if removed > 0 {
+                    debug!("cache sweep removed {} expired entries", removed);
+                }
+            }
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().expect("cache lock poisoned").len();
+        CacheStats {
+            entries,
+            capacity: self.config.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            refreshes: self.refreshes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(CacheConfig::default())
+    }
+}
+
+pub(crate) fn current_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize, ttl: Duration) -> ResponseCache {
+        ResponseCache::new(CacheConfig {
+            capacity,
+            ttls: HashMap::from([(Source::MyAnimeList, ttl)]),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_lru_eviction_and_stats() {
+        let cache = cache(2, DEFAULT_CACHE_TTL);
+        let now = current_millis();
+        cache.insert(Source::MyAnimeList, "a".to_string(), now, 1u64);
+        cache.insert(Source::MyAnimeList, "b".to_string(), now, 2u64);
+        // Touch "a" so "b" is least recently used.
+        assert_eq!(cache.get::<u64>("a"), Some((now, 1)));
+        cache.insert(Source::MyAnimeList, "c".to_string(), now, 3u64);
+
+        assert_eq!(cache.get::<u64>("b"), None);
+        assert_eq!(cache.get::<u64>("c"), Some((now, 3)));
+        // Replacing a key is not an eviction.
+        cache.insert(Source::MyAnimeList, "c".to_string(), now, 4u64);
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 1);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let cache = cache(8, Duration::ZERO);
+        let now = current_millis();
+        cache.insert(Source::MyAnimeList, "a".to_string(), now, 1u64);
+        cache.insert(Source::MyAnimeList, "b".to_string(), now, 2u64);
+        // Other providers keep the default TTL.
+        cache.insert(Source::AniList, "c".to_string(), now, 3u64);
+
+        assert_eq!(cache.get::<u64>("a"), None);
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(cache.get::<u64>("c"), Some((now, 3)));
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.expirations, 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_fetch_coalesces() {
+        let cache = Arc::new(cache(8, DEFAULT_CACHE_TTL));
+        let fetches = Arc::new(AtomicU64::new(0));
+
+        let callers: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let fetches = Arc::clone(&fetches);
+                tokio::spawn(async move {
+                    cache
+                        .get_or_fetch(Source::MyAnimeList, "mal:naruto", None, || async move {
+                            fetches.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok::<_, EnclaveError>(42u64)
+                        })
+                        .await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for caller in callers {
+            results.push(caller.await.unwrap().unwrap());
+        }
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|(freshness, value)| {
+            *value == 42 && freshness.fetched_at_ms == results[0].0.fetched_at_ms
+        }));
+        let upstream = results
+            .iter()
+            .filter(|(f, _)| f.origin == CacheOrigin::Upstream)
+            .count();
+        assert_eq!(upstream, 1);
+        assert_eq!(cache.stats().coalesced, 7);
+        assert!(cache.inflight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_fetch_shares_errors_without_caching() {
+        let cache = Arc::new(cache(8, DEFAULT_CACHE_TTL));
+        let result = cache
+            .get_or_fetch(Source::MyAnimeList, "mal:none", None, || async {
+                Err::<u64, _>(EnclaveError::NotFound("none".to_string()))
+            })
+            .await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+        assert_eq!(cache.stats().entries, 0);
+
+        let result = cache
+            .get_or_fetch(Source::MyAnimeList, "mal:none", None, || async {
+                Ok::<_, EnclaveError>(1u64)
+            })
+            .await;
+        assert_eq!(result.unwrap().1, 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_age_and_refresh_ahead() {
+        let cache = Arc::new(ResponseCache::new(CacheConfig {
+            refresh_ahead: DEFAULT_CACHE_TTL,
+            refresh_min_hits: 2,
+            ..Default::default()
+        }));
+        let fetches = Arc::new(AtomicU64::new(0));
+        let fetch = |fetches: &Arc<AtomicU64>| {
+            let fetches = Arc::clone(fetches);
+            move || async move { Ok::<_, EnclaveError>(fetches.fetch_add(1, Ordering::SeqCst)) }
+        };
+        let get =
+            |max_age_ms| cache.get_or_fetch(Source::MyAnimeList, "k", max_age_ms, fetch(&fetches));
+
+        let (freshness, value) = get(None).await.unwrap();
+        assert_eq!((freshness.origin, value), (CacheOrigin::Upstream, 0));
+
+        // The first hit does not make the key hot, the second starts a refresh.
+        let (freshness, value) = get(None).await.unwrap();
+        assert_eq!((freshness.origin, value), (CacheOrigin::Cache, 0));
+        assert_eq!(cache.stats().refreshes, 0);
+        let (freshness, value) = get(None).await.unwrap();
+        assert_eq!((freshness.origin, value), (CacheOrigin::Cache, 0));
+        assert_eq!(cache.stats().refreshes, 1);
+        while fetches.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(cache.get::<u64>("k").map(|(_, v)| v), Some(1));
+
+        // A value older than max_age_ms is fetched again.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let (freshness, value) = get(Some(0)).await.unwrap();
+        assert_eq!((freshness.origin, value), (CacheOrigin::Upstream, 2));
+    }
+
+    #[test]
+    fn test_type_mismatch_is_a_miss() {
+        let cache = cache(8, DEFAULT_CACHE_TTL);
+        cache.insert(Source::MyAnimeList, "a".to_string(), current_millis(), 1u64);
+        assert_eq!(cache.get::<String>("a"), None);
+    }
+}
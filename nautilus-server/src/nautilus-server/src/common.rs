@@ -1,23 +1,36 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::allowlist::Allowlist;
+use crate::app::Source;
+use crate::cache::{CacheStats, Freshness};
+use crate::limiter::BreakerStatus;
+use crate::metrics::METRICS;
+use crate::probe::ProbeResult;
+use crate::readiness::ReadinessReport;
 use crate::AppState;
 use crate::EnclaveError;
-use axum::{extract::State, Json};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use fastcrypto::hash::{HashFunction, Sha256};
 use fastcrypto::traits::Signer;
 use fastcrypto::{encoding::Encoding, traits::ToFromBytes};
 use fastcrypto::{encoding::Hex, traits::KeyPair as FcKeyPair};
+#[cfg(not(feature = "dev-attestation"))]
 use nsm_api::api::{Request as NsmRequest, Response as NsmResponse};
+#[cfg(not(feature = "dev-attestation"))]
 use nsm_api::driver;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(not(feature = "dev-attestation"))]
 use serde_bytes::ByteBuf;
 use serde_repr::Deserialize_repr;
 use serde_repr::Serialize_repr;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fmt::Debug;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
 use tracing::info;
 
 use fastcrypto::ed25519::Ed25519KeyPair;
@@ -32,11 +45,14 @@ pub struct IntentMessage<T: Serialize> {
 }
 
 /// Intent scope enum. Add new scope here if needed, each corresponds to a
-/// scope for signing. Replace in with your own intent per message type being signed by the enclave.
+/// scope for signing. Every provider signs under its own scope, so metrics
+/// from one source can never be passed off as another's.
 #[derive(Serialize_repr, Deserialize_repr, Debug)]
 #[repr(u8)]
 pub enum IntentScope {
-    ProcessData = 0,
+    MyAnimeList = 0,
+    AniList = 1,
+    MangaDex = 2,
 }
 
 impl<T: Serialize + Debug> IntentMessage<T> {
@@ -54,12 +70,125 @@ impl<T: Serialize + Debug> IntentMessage<T> {
 pub struct ProcessedDataResponse<T> {
     pub response: T,
     pub signature: String,
+    /// Unsigned cache origin and age of the signed data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freshness: Option<Freshness>,
+}
+
+impl<T> ProcessedDataResponse<T> {
+    pub fn with_freshness(self, freshness: Freshness) -> Self {
+        Self {
+            freshness: Some(freshness),
+            ..self
+        }
+    }
 }
 
 /// Wrapper struct containing the request payload.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessDataRequest<T> {
     pub payload: T,
+    /// ODX IP token the response is minted for, signed into `RequestBinding`.
+    #[serde(default)]
+    pub ip_token_id: Option<ObjectId>,
+    /// Caller chosen nonce, signed into `RequestBinding`. Accepted as a JSON
+    /// number or a decimal string, since JavaScript numbers can't hold every u64.
+    #[serde(default, deserialize_with = "deserialize_nonce")]
+    pub nonce: Option<u64>,
+    /// Refetch upstream instead of serving cached data older than this.
+    #[serde(default)]
+    pub max_age_ms: Option<u64>,
+}
+
+impl<T> ProcessDataRequest<T> {
+    /// The binding to sign into the response for this request.
+    pub fn binding(&self) -> RequestBinding {
+        RequestBinding {
+            ip_token_id: self.ip_token_id,
+            nonce: self.nonce,
+        }
+    }
+}
+
+fn deserialize_nonce<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Nonce {
+        Number(u64),
+        String(String),
+    }
+
+    match Option::<Nonce>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Nonce::Number(n)) => Ok(Some(n)),
+        Some(Nonce::String(s)) => s
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("invalid nonce {s:?}: {e}"))),
+    }
+}
+
+/// A 32-byte Sui object id. Serialized as a `0x` hex string in JSON and as
+/// 32 raw bytes in BCS, so Move can peel it as an `address`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ObjectId(pub [u8; 32]);
+
+impl FromStr for ObjectId {
+    type Err = EnclaveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim();
+        let hex = hex.strip_prefix("0x").unwrap_or(hex);
+        if hex.is_empty() || hex.len() > 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(EnclaveError::InvalidInput(format!(
+                "invalid object id: {s}"
+            )));
+        }
+        // Left pad short ids such as 0x2, the same way Sui normalizes addresses.
+        let padded = format!("{hex:0>64}");
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&padded[2 * i..2 * i + 2], 16)
+                .map_err(|e| EnclaveError::InvalidInput(format!("invalid object id: {e}")))?;
+        }
+        Ok(ObjectId(bytes))
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", Hex::encode(self.0))
+    }
+}
+
+impl Serialize for ObjectId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(ObjectId)
+        }
+    }
+}
+
+/// Request context signed into every metrics payload, so a signature minted
+/// for one IP token or request cannot be replayed against another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RequestBinding {
+    pub ip_token_id: Option<ObjectId>,
+    pub nonce: Option<u64>,
 }
 
 /// Sign the bcs bytes of the the payload with keypair.
@@ -77,145 +206,326 @@ pub fn to_signed_response<T: Serialize + Clone>(
 
     let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
     let sig = kp.sign(&signing_payload);
+    METRICS.record_signature(&intent_msg.intent);
     ProcessedDataResponse {
         response: intent_msg,
         signature: Hex::encode(sig),
+        freshness: None,
+    }
+}
+
+/// ==== FIXED-POINT PAYLOAD ENCODING ====
+/// Layout version carried as the first field of every signed metrics payload.
+/// Bump it whenever a signed struct changes shape so Move decoders can reject
+/// payloads they do not understand.
+pub const METRICS_PAYLOAD_VERSION: u8 = 6;
+
+/// Rating precision scale, matches `odx::datatypes::RATING_SCALE` (850 = 8.50).
+pub const RATING_SCALE: u64 = 100;
+
+/// Match confidence scale, in basis points (10_000 = exact title match).
+pub const MATCH_CONFIDENCE_SCALE: u64 = 10_000;
+
+/// Convert an upstream decimal into u64 fixed point with the given scale,
+/// rounding to nearest. Negative and non-finite values map to 0.
+pub fn to_fixed_point(value: f64, scale: u64) -> u64 {
+    if !value.is_finite() || value <= 0.0 {
+        return 0;
+    }
+    (value * scale as f64).round() as u64
+}
+
+/// Convert an upstream count into u64, mapping negative values to 0.
+pub fn to_count(value: i64) -> u64 {
+    u64::try_from(value).unwrap_or(0)
+}
+
+/// Unwrap a field the upstream record must carry. Missing fields are rejected
+/// with `IncompleteRecord` rather than signed as zero, since a signed zero
+/// reads as a real value.
+pub fn require<T>(value: Option<T>, provider: &str, field: &str) -> Result<T, EnclaveError> {
+    value.ok_or_else(|| {
+        EnclaveError::IncompleteRecord(format!("{provider} record is missing {field}"))
+    })
+}
+
+/// Read an optional numeric setting from the environment.
+pub(crate) fn env_u64(var: &str) -> anyhow::Result<Option<u64>> {
+    match std::env::var(var) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid {var}: {e}")),
+        Err(_) => Ok(None),
     }
 }
 
 /// ==== HEALTHCHECK, GET ATTESTASTION ENDPOINT IMPL ====
+/// Largest `nonce` or `user_data` the NSM accepts, in bytes.
+pub const MAX_ATTESTATION_FIELD_LEN: usize = 512;
+
+/// Optional attestation inputs, accepted as query parameters on GET or as a
+/// JSON body on POST.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GetAttestationRequest {
+    /// Hex encoded verifier nonce, echoed in the document for freshness.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// Hex encoded user data bound into the document.
+    #[serde(default)]
+    pub user_data: Option<String>,
+    /// Set `user_data` to the SHA-256 digest of the running `ConfigCommitment`.
+    /// Cannot be combined with `user_data`.
+    #[serde(default)]
+    pub commit_config: bool,
+}
+
+/// Running configuration that can be committed into an attestation. Its
+/// digest is SHA-256 over the BCS bytes of this struct.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigCommitment {
+    /// Hosts the enclave may contact, sorted. See `Allowlist::hosts`.
+    pub allowed_hosts: Vec<String>,
+    /// Providers compiled into this build, e.g. `["myanimelist"]`.
+    pub providers: Vec<String>,
+}
+
+impl ConfigCommitment {
+    /// Capture the configuration of the running enclave.
+    pub fn current(allowlist: &Allowlist) -> Self {
+        Self {
+            allowed_hosts: allowlist.hosts(),
+            providers: Source::enabled().iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// SHA-256 digest of the BCS bytes of the commitment.
+    pub fn digest(&self) -> [u8; 32] {
+        let bytes = bcs::to_bytes(self).expect("should not fail");
+        Sha256::digest(bytes).digest
+    }
+}
+
 /// Response for get attestation.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetAttestationResponse {
     /// Attestation document serialized in Hex.
     pub attestation: String,
+    /// Preimage of `user_data` when `commit_config` was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigCommitment>,
 }
 
 /// Endpoint that returns an attestation committed
 /// to the enclave's public key.
 pub async fn get_attestation(
     State(state): State<Arc<AppState>>,
+    Query(request): Query<GetAttestationRequest>,
+) -> Result<Json<GetAttestationResponse>, EnclaveError> {
+    attest(&state, request)
+}
+
+/// Same as `get_attestation`, with the inputs in a JSON body.
+pub async fn post_attestation(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<GetAttestationRequest>,
+) -> Result<Json<GetAttestationResponse>, EnclaveError> {
+    attest(&state, request)
+}
+
+fn attest(
+    state: &AppState,
+    request: GetAttestationRequest,
 ) -> Result<Json<GetAttestationResponse>, EnclaveError> {
     info!("get attestation called");
 
+    let nonce = decode_attestation_field("nonce", request.nonce.as_deref())?;
+    let mut user_data = decode_attestation_field("user_data", request.user_data.as_deref())?;
+    let config = if request.commit_config {
+        if user_data.is_some() {
+            return Err(EnclaveError::InvalidInput(
+                "user_data cannot be combined with commit_config".to_string(),
+            ));
+        }
+        let config = ConfigCommitment::current(state.upstream.allowlist());
+        user_data = Some(config.digest().to_vec());
+        Some(config)
+    } else {
+        None
+    };
+
     let pk = state.eph_kp.public();
+    let document = nsm_attestation(pk.as_bytes().to_vec(), user_data, nonce)?;
+
+    Ok(Json(GetAttestationResponse {
+        attestation: Hex::encode(document),
+        config,
+    }))
+}
+
+/// Decode an optional hex attestation input, enforcing the NSM size limit.
+fn decode_attestation_field(
+    name: &str,
+    value: Option<&str>,
+) -> Result<Option<Vec<u8>>, EnclaveError> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    let bytes = Hex::decode(value.strip_prefix("0x").unwrap_or(value))
+        .map_err(|e| EnclaveError::InvalidInput(format!("invalid {name}: {e}")))?;
+    if bytes.len() > MAX_ATTESTATION_FIELD_LEN {
+        return Err(EnclaveError::InvalidInput(format!(
+            "{name} exceeds {MAX_ATTESTATION_FIELD_LEN} bytes"
+        )));
+    }
+    Ok(Some(bytes))
+}
+
+/// Request an attestation document committed to `public_key` from the NSM driver.
+#[cfg(not(feature = "dev-attestation"))]
+fn nsm_attestation(
+    public_key: Vec<u8>,
+    user_data: Option<Vec<u8>>,
+    nonce: Option<Vec<u8>>,
+) -> Result<Vec<u8>, EnclaveError> {
     let fd = driver::nsm_init();
 
     // Send attestation request to NSM driver with public key set.
     let request = NsmRequest::Attestation {
-        user_data: None,
-        nonce: None,
-        public_key: Some(ByteBuf::from(pk.as_bytes().to_vec())),
+        user_data: user_data.map(ByteBuf::from),
+        nonce: nonce.map(ByteBuf::from),
+        public_key: Some(ByteBuf::from(public_key)),
     };
 
     let response = driver::nsm_process_request(fd, request);
+    driver::nsm_exit(fd);
     match response {
-        NsmResponse::Attestation { document } => {
-            driver::nsm_exit(fd);
-            Ok(Json(GetAttestationResponse {
-                attestation: Hex::encode(document),
-            }))
-        }
-        _ => {
-            driver::nsm_exit(fd);
-            Err(EnclaveError::GenericError(
-                "unexpected response".to_string(),
-            ))
-        }
+        NsmResponse::Attestation { document } => Ok(document),
+        _ => Err(EnclaveError::Internal(
+            "unexpected NSM response".to_string(),
+        )),
     }
 }
 
+/// Request an attestation document committed to `public_key` from the mock NSM.
+#[cfg(feature = "dev-attestation")]
+fn nsm_attestation(
+    public_key: Vec<u8>,
+    user_data: Option<Vec<u8>>,
+    nonce: Option<Vec<u8>>,
+) -> Result<Vec<u8>, EnclaveError> {
+    Ok(crate::attestation::mock::MOCK_NSM.attest(Some(public_key), user_data, nonce))
+}
+
 /// Health check response.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HealthCheckResponse {
     /// Hex encoded public key booted on enclave.
     pub pk: String,
-    /// Status of endpoint connectivity checks
-    pub endpoints_status: HashMap<String, bool>,
+    /// Outcome of the background prober's last run per allowlisted host, see
+    /// `probe::ProbeSpec`.
+    pub endpoints_status: BTreeMap<String, ProbeResult>,
+    /// The verdict `/readyz` serves, see `readiness::Readiness`.
+    pub readiness: ReadinessReport,
+    /// Response cache counters, for sizing `CACHE_CAPACITY`.
+    pub cache: CacheStats,
+    /// Circuit breaker status per upstream host contacted so far.
+    pub upstreams: BTreeMap<String, BreakerStatus>,
+    /// Hex SHA-256 of the egress allowlist, see `Allowlist::digest`.
+    pub allowlist_digest: String,
 }
 
-/// Endpoint that health checks the enclave connectivity to all
-/// domains and returns the enclave's public key.
+/// Endpoint that returns the enclave's public key and its connectivity to
+/// all domains as last probed by the background prober. It never contacts
+/// the upstreams itself, so polling it cannot fan out to every provider.
 pub async fn health_check(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<HealthCheckResponse>, EnclaveError> {
     let pk = state.eph_kp.public();
-
-    // Create HTTP client with timeout
-    let client = Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to create HTTP client: {e}")))?;
-
-    // Load allowed endpoints from YAML file
-    let endpoints_status = match std::fs::read_to_string("allowed_endpoints.yaml") {
-        Ok(yaml_content) => {
-            match serde_yaml::from_str::<serde_yaml::Value>(&yaml_content) {
-                Ok(yaml_value) => {
-                    let mut status_map = HashMap::new();
-
-                    if let Some(endpoints) =
-                        yaml_value.get("endpoints").and_then(|e| e.as_sequence())
-                    {
-                        for endpoint in endpoints {
-                            if let Some(endpoint_str) = endpoint.as_str() {
-                                // Check connectivity to each endpoint
-                                let url = if endpoint_str.contains(".amazonaws.com") {
-                                    format!("https://{endpoint_str}/ping")
-                                } else {
-                                    format!("https://{endpoint_str}")
-                                };
-
-                                let is_reachable = match client.get(&url).send().await {
-                                    Ok(response) => {
-                                        if endpoint_str.contains(".amazonaws.com") {
-                                            // For AWS endpoints, check if response body contains "healthy"
-                                            match response.text().await {
-                                                Ok(body) => body.to_lowercase().contains("healthy"),
-                                                Err(e) => {
-                                                    info!(
-                                                        "Failed to read response body from {}: {}",
-                                                        endpoint_str, e
-                                                    );
-                                                    false
-                                                }
-                                            }
-                                        } else {
-                                            // For non-AWS endpoints, check for 200 status
-                                            response.status().is_success()
-                                        }
-                                    }
-                                    Err(e) => {
-                                        info!("Failed to connect to {}: {}", endpoint_str, e);
-                                        false
-                                    }
-                                };
-
-                                status_map.insert(endpoint_str.to_string(), is_reachable);
-                                info!(
-                                    "Checked endpoint {}: reachable = {}",
-                                    endpoint_str, is_reachable
-                                );
-                            }
-                        }
-                    }
-
-                    status_map
-                }
-                Err(e) => {
-                    info!("Failed to parse YAML: {}", e);
-                    HashMap::new()
-                }
-            }
-        }
-        Err(e) => {
-            info!("Failed to read allowed_endpoints.yaml: {}", e);
-            HashMap::new()
-        }
-    };
-
     Ok(Json(HealthCheckResponse {
         pk: Hex::encode(pk.as_bytes()),
-        endpoints_status,
+        endpoints_status: state.readiness.probes(),
+        readiness: state.readiness.report(),
+        cache: state.cache.stats(),
+        upstreams: state.upstream.breakers(),
+        allowlist_digest: Hex::encode(state.upstream.allowlist().digest()),
     }))
 }
+
+/// Liveness: the process is up and serving requests. Does no I/O.
+pub async fn livez() -> &'static str {
+    "ok"
+}
+
+/// Readiness: 200 when the enclave can sign metrics from at least one
+/// provider, 503 otherwise. Serves the verdict cached by the background
+/// prober, see `readiness::Readiness`.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadinessReport>) {
+    let report = state.readiness.report();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_attestation_field() {
+        assert_eq!(decode_attestation_field("nonce", None).unwrap(), None);
+        assert_eq!(
+            decode_attestation_field("nonce", Some("0x0a0b")).unwrap(),
+            Some(vec![0x0a, 0x0b])
+        );
+        assert!(decode_attestation_field("nonce", Some("zz")).is_err());
+        let too_long = "00".repeat(MAX_ATTESTATION_FIELD_LEN + 1);
+        assert!(decode_attestation_field("user_data", Some(&too_long)).is_err());
+    }
+
+    #[test]
+    fn test_config_commitment_digest() {
+        let config = ConfigCommitment {
+            allowed_hosts: vec!["api.myanimelist.net".to_string()],
+            providers: vec!["myanimelist".to_string()],
+        };
+        let mut other = config.clone();
+        other.providers.push("anilist".to_string());
+        assert_eq!(config.digest(), config.clone().digest());
+        assert_ne!(config.digest(), other.digest());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_reports_prober_state() {
+        use crate::test_utils::app_state;
+        use crate::upstream::UpstreamClient;
+
+        let state = Arc::new(app_state(UpstreamClient::for_tests()));
+        let Json(health) = health_check(State(Arc::clone(&state))).await.unwrap();
+        assert!(health.endpoints_status.is_empty());
+        assert!(!health.readiness.ready);
+
+        let probes = BTreeMap::from([(
+            "api.mangadex.org".to_string(),
+            ProbeResult {
+                healthy: true,
+                latency_ms: 8,
+                status: Some(200),
+                error: None,
+            },
+        )]);
+        state.readiness.record(
+            probes.clone(),
+            &BTreeMap::new(),
+            crate::cache::current_millis(),
+        );
+        let Json(health) = health_check(State(state)).await.unwrap();
+        assert_eq!(health.endpoints_status, probes);
+        assert_eq!(health.readiness.available, vec!["api.mangadex.org"]);
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Server configuration: the listen address, the browser origins allowed to
+//! call the enclave, and request size and time limits. Read from an optional
+//! YAML file named by `SERVER_CONFIG_FILE`, then overridden by environment
+//! variables, and validated before the server binds.
+
+use crate::common::env_u64;
+use crate::upstream::UpstreamConfig;
+use crate::EnclaveError;
+use axum::extract::{Request, State};
+use axum::http::{HeaderValue, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Default listen address. run.sh forwards VSOCK port 3000 to it.
+pub const DEFAULT_LISTEN_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3000));
+
+/// Default maximum size of a request body.
+pub const DEFAULT_BODY_LIMIT_BYTES: usize = 64 * 1024;
+
+/// Default time allowed to handle a request, above the 40 seconds an upstream
+/// request with the default retry policy can take.
+pub const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 45_000;
+
+/// Server configuration, as in this YAML file:
+///
+/// ```yaml
+/// listen_addr: 0.0.0.0:3000
+/// cors_origins:
+///   - https://app.example.com
+/// body_limit_bytes: 65536
+/// request_timeout_ms: 45000
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    /// Origins browsers may call the enclave from, e.g.
+    /// `https://app.example.com`, or `*` for any. Empty allows none.
+    pub cors_origins: Vec<String>,
+    pub body_limit_bytes: usize,
+    pub request_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: DEFAULT_LISTEN_ADDR,
+            cors_origins: Vec::new(),
+            body_limit_bytes: DEFAULT_BODY_LIMIT_BYTES,
+            request_timeout_ms: DEFAULT_REQUEST_TIMEOUT_MS,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read the file named by `SERVER_CONFIG_FILE`, if set, then apply
+    /// `LISTEN_ADDR`, `CORS_ALLOWED_ORIGINS` (comma separated),
+    /// `BODY_LIMIT_BYTES` and `REQUEST_TIMEOUT_MS`, and validate the result.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut config = match std::env::var("SERVER_CONFIG_FILE") {
+            Ok(path) => {
+                let yaml = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow::anyhow!("cannot read SERVER_CONFIG_FILE {path}: {e}"))?;
+                Self::from_yaml(&yaml)
+                    .map_err(|e| anyhow::anyhow!("invalid SERVER_CONFIG_FILE {path}: {e}"))?
+            }
+            Err(_) => Self::default(),
+        };
+
+        if let Ok(addr) = std::env::var("LISTEN_ADDR") {
+            config.listen_addr = addr.trim().parse().map_err(|e| {
+                anyhow::anyhow!("LISTEN_ADDR must be an address like 0.0.0.0:3000: {e}")
+            })?;
+        }
+        if let Ok(origins) = std::env::var("CORS_ALLOWED_ORIGINS") {
+            config.cors_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(bytes) = env_u64("BODY_LIMIT_BYTES")? {
+            config.body_limit_bytes = bytes as usize;
+        }
+        if let Some(ms) = env_u64("REQUEST_TIMEOUT_MS")? {
+            config.request_timeout_ms = ms;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml(yaml: &str) -> anyhow::Result<Self> {
+        if yaml.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_yaml::from_str(yaml)?)
+    }
+
+    /// Check limits and normalize `cors_origins` to `scheme://host[:port]`.
+    pub fn validate(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.body_limit_bytes > 0,
+            "body_limit_bytes must be positive"
+        );
+        anyhow::ensure!(
+            self.request_timeout_ms > 0,
+            "request_timeout_ms must be positive"
+        );
+        if self.cors_origins.iter().any(|origin| origin == "*") {
+            anyhow::ensure!(
+                self.cors_origins.len() == 1,
+                "cors_origins cannot combine '*' with other origins"
+            );
+            return Ok(());
+        }
+        self.cors_origins = self
+            .cors_origins
+            .iter()
+            .map(|origin| normalize_origin(origin))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(())
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    /// Check that a request outlasts an upstream request with all of its
+    /// retries, so callers get the upstream error rather than a bare
+    /// `timeout`.
+    pub fn check_upstream_budget(&self, upstream: &UpstreamConfig) -> anyhow::Result<()> {
+        let budget = upstream.worst_case();
+        anyhow::ensure!(
+            self.request_timeout() > budget,
+            "request_timeout_ms {} must exceed the {}ms an upstream request with its retries can take; \
+             raise it or lower UPSTREAM_REQUEST_TIMEOUT_MS, UPSTREAM_MAX_RETRIES or UPSTREAM_RETRY_MAX_MS",
+            self.request_timeout_ms,
+            budget.as_millis()
+        );
+        Ok(())
+    }
+
+    /// CORS policy allowing `cors_origins` to send GET and POST requests.
+    pub fn cors_layer(&self) -> anyhow::Result<CorsLayer> {
+        let cors = CorsLayer::new()
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers(Any);
+        if self.cors_origins.iter().any(|origin| origin == "*") {
+            return Ok(cors.allow_origin(Any));
+        }
+        let origins = self
+            .cors_origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(cors.allow_origin(AllowOrigin::list(origins)))
+    }
+}
+
+/// `https://App.example.com/` names the origin `https://app.example.com`.
+fn normalize_origin(origin: &str) -> anyhow::Result<String> {
+    let url = reqwest::Url::parse(origin.trim())
+        .map_err(|e| anyhow::anyhow!("cors origin {origin:?} is not a URL: {e}"))?;
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "https"),
+        "cors origin {origin:?} must be http or https"
+    );
+    anyhow::ensure!(
+        url.path() == "/"
+            && url.query().is_none()
+            && url.fragment().is_none()
+            && url.username().is_empty(),
+        "cors origin {origin:?} must be scheme://host[:port] only"
+    );
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("cors origin {origin:?} has no host"))?;
+    Ok(match url.port() {
+        Some(port) => format!("{}://{}:{}", url.scheme(), host, port),
+        None => format!("{}://{}", url.scheme(), host),
+    })
+}
+
+/// Middleware failing requests that take longer than the configured timeout
+/// with `timeout` (504).
+pub async fn request_timeout(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => EnclaveError::Timeout(format!(
+            "request did not complete within {}ms",
+            timeout.as_millis()
+        ))
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config_from_yaml() {
+        assert_eq!(
+            ServerConfig::from_yaml("").unwrap(),
+            ServerConfig::default()
+        );
+
+        let mut config = ServerConfig::from_yaml(
+            "listen_addr: 127.0.0.1:8080\ncors_origins:\n  - https://App.example.com/\n  - http://localhost:5173\nbody_limit_bytes: 1024\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.listen_addr, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(
+            config.cors_origins,
+            vec!["https://app.example.com", "http://localhost:5173"]
+        );
+        assert_eq!(config.body_limit_bytes, 1024);
+        assert_eq!(
+            config.request_timeout(),
+            Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS)
+        );
+        assert!(config.cors_layer().is_ok());
+
+        assert!(ServerConfig::from_yaml("listen_addr: localhost\n").is_err());
+        assert!(ServerConfig::from_yaml("port: 3000\n").is_err());
+    }
+
+    #[test]
+    fn test_server_config_validation() {
+        let invalid = [
+            "body_limit_bytes: 0\n",
+            "request_timeout_ms: 0\n",
+            "cors_origins: ['*', 'https://app.example.com']\n",
+            "cors_origins: ['app.example.com']\n",
+            "cors_origins: ['ftp://app.example.com']\n",
+            "cors_origins: ['https://app.example.com/callback']\n",
+        ];
+        for yaml in invalid {
+            let mut config = ServerConfig::from_yaml(yaml).unwrap();
+            assert!(config.validate().is_err(), "{yaml}");
+        }
+
+        let mut any = ServerConfig::from_yaml("cors_origins: ['*']\n").unwrap();
+        assert!(any.validate().is_ok());
+        assert!(any.cors_layer().is_ok());
+    }
+
+    #[test]
+    fn test_request_timeout_covers_upstream_retries() {
+        let upstream = UpstreamConfig::default();
+        assert!(ServerConfig::default()
+            .check_upstream_budget(&upstream)
+            .is_ok());
+        let short = ServerConfig::from_yaml("request_timeout_ms: 30000\n").unwrap();
+        assert!(short.check_upstream_budget(&upstream).is_err());
+        let single_attempt = UpstreamConfig {
+            max_retries: 0,
+            ..Default::default()
+        };
+        assert!(short.check_upstream_budget(&single_attempt).is_ok());
+    }
+}
@@ -1,26 +1,70 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::cache::ResponseCache;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Json;
 use fastcrypto::ed25519::Ed25519KeyPair;
+use readiness::Readiness;
 use serde_json::json;
 use std::fmt;
+use std::sync::Arc;
+use upstream::UpstreamClient;
+
+#[cfg(not(any(feature = "myanimelist", feature = "anilist", feature = "mangadex")))]
+compile_error!("enable at least one provider feature: `myanimelist`, `anilist` or `mangadex`");
 
 mod apps {
-    // Integrated, single app: MyAnimeList processing
+    // MyAnimeList processing
+    #[cfg(feature = "myanimelist")]
     #[path = "myanimelist/mod.rs"]
     pub mod myanimelist;
+
+    // AniList processing
+    #[cfg(feature = "anilist")]
+    #[path = "anilist/mod.rs"]
+    pub mod anilist;
+
+    // MangaDex processing
+    #[cfg(feature = "mangadex")]
+    #[path = "mangadex/mod.rs"]
+    pub mod mangadex;
+
+    // Routes `process_data` to the provider named by the request's `source`
+    #[path = "registry.rs"]
+    pub mod registry;
 }
 
 pub mod app {
-    // Re-export the integrated MyAnimeList handler as `app::process_data`
-    pub use crate::apps::myanimelist::*;
+    // Re-export the provider registry as `app::process_data`
+    pub use crate::apps::registry::*;
+
+    #[cfg(feature = "myanimelist")]
+    pub use crate::apps::myanimelist;
+
+    #[cfg(feature = "anilist")]
+    pub use crate::apps::anilist;
+
+    #[cfg(feature = "mangadex")]
+    pub use crate::apps::mangadex;
 }
 
+pub mod allowlist;
+pub mod attestation;
+pub mod cache;
 pub mod common;
+pub mod config;
+pub mod limiter;
+pub mod logging;
+pub mod metrics;
+pub mod probe;
+pub mod readiness;
+pub mod upstream;
+
+#[cfg(test)]
+mod test_utils;
 
 /// App state, at minimum needs to maintain the ephemeral keypair.  
 pub struct AppState {
@@ -28,33 +72,229 @@ pub struct AppState {
     pub eph_kp: Ed25519KeyPair,
     /// API key when querying api.weatherapi.com
     pub api_key: String,
+    /// Unsigned provider metrics shared by all requests.
+    pub cache: Arc<ResponseCache>,
+    /// Pooled HTTP client for upstream provider APIs.
+    pub upstream: UpstreamClient,
+    /// Readiness verdict kept current by the background prober.
+    pub readiness: Arc<Readiness>,
 }
 
 /// Implement IntoResponse for EnclaveError.
 impl IntoResponse for EnclaveError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            EnclaveError::GenericError(e) => (StatusCode::BAD_REQUEST, e),
-        };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        let mut body = json!({
+            "error": self.to_string(),
+            "code": self.code(),
+            "retryable": self.is_retryable(),
+        });
+        if let Some(request_id) = logging::current_request_id() {
+            body["request_id"] = json!(request_id);
+        }
+        (self.status(), Json(body)).into_response()
     }
 }
 
 /// Enclave errors enum.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EnclaveError {
-    GenericError(String),
+    /// The request is malformed or fails validation.
+    InvalidInput(String),
+    /// The requested `source` is unknown or not compiled into this enclave build.
+    UnsupportedSource(String),
+    /// The upstream provider has no record matching the request.
+    NotFound(String),
+    /// The upstream record exists but lacks a field that would be signed.
+    IncompleteRecord(String),
+    /// Several upstream records match the request too closely to pick one.
+    Ambiguous(String),
+    /// The upstream provider failed, timed out or returned an unusable response.
+    UpstreamUnavailable(String),
+    /// The upstream provider is throttling the enclave.
+    UpstreamRateLimited(String),
+    /// The enclave's own rate limit for the upstream host is exhausted.
+    RateLimited(String),
+    /// The circuit breaker for the upstream host is open after repeated failures.
+    CircuitOpen(String),
+    /// The request took longer than the server's request timeout.
+    Timeout(String),
+    /// The enclave is missing or has invalid configuration.
+    Misconfigured(String),
+    /// Unexpected failure inside the enclave.
+    Internal(String),
+}
+
+impl EnclaveError {
+    /// HTTP status returned for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            EnclaveError::InvalidInput(_) | EnclaveError::UnsupportedSource(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            EnclaveError::NotFound(_) => StatusCode::NOT_FOUND,
+            EnclaveError::Ambiguous(_) => StatusCode::CONFLICT,
+            EnclaveError::IncompleteRecord(_) | EnclaveError::UpstreamUnavailable(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            EnclaveError::UpstreamRateLimited(_) | EnclaveError::RateLimited(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            EnclaveError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            EnclaveError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            EnclaveError::Misconfigured(_) | EnclaveError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Machine-readable error code returned in the JSON body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            EnclaveError::InvalidInput(_) => "invalid_input",
+            EnclaveError::UnsupportedSource(_) => "unsupported_source",
+            EnclaveError::NotFound(_) => "not_found",
+            EnclaveError::IncompleteRecord(_) => "incomplete_record",
+            EnclaveError::Ambiguous(_) => "ambiguous",
+            EnclaveError::UpstreamUnavailable(_) => "upstream_unavailable",
+            EnclaveError::UpstreamRateLimited(_) => "upstream_rate_limited",
+            EnclaveError::RateLimited(_) => "rate_limited",
+            EnclaveError::CircuitOpen(_) => "circuit_open",
+            EnclaveError::Timeout(_) => "timeout",
+            EnclaveError::Misconfigured(_) => "misconfigured",
+            EnclaveError::Internal(_) => "internal",
+        }
+    }
+
+    /// Whether the same request may succeed if retried later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            EnclaveError::UpstreamUnavailable(_)
+                | EnclaveError::UpstreamRateLimited(_)
+                | EnclaveError::RateLimited(_)
+                | EnclaveError::CircuitOpen(_)
+                | EnclaveError::Timeout(_)
+        )
+    }
+
+    /// Map a non-success upstream HTTP status to an error.
+    pub fn from_upstream_status(provider: &str, status: reqwest::StatusCode) -> Self {
+        let message = format!("{provider} returned status {status}");
+        match status.as_u16() {
+            429 => EnclaveError::UpstreamRateLimited(message),
+            404 => EnclaveError::NotFound(message),
+            401 | 403 => EnclaveError::Misconfigured(message),
+            _ => EnclaveError::UpstreamUnavailable(message),
+        }
+    }
 }
 
 impl fmt::Display for EnclaveError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            EnclaveError::GenericError(e) => write!(f, "{e}"),
+            EnclaveError::UnsupportedSource(source) => write!(f, "unsupported source: {source}"),
+            EnclaveError::InvalidInput(e)
+            | EnclaveError::NotFound(e)
+            | EnclaveError::IncompleteRecord(e)
+            | EnclaveError::Ambiguous(e)
+            | EnclaveError::UpstreamUnavailable(e)
+            | EnclaveError::UpstreamRateLimited(e)
+            | EnclaveError::RateLimited(e)
+            | EnclaveError::CircuitOpen(e)
+            | EnclaveError::Timeout(e)
+            | EnclaveError::Misconfigured(e)
+            | EnclaveError::Internal(e) => write!(f, "{e}"),
         }
     }
 }
 
 impl std::error::Error for EnclaveError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_status_and_code() {
+        let cases = [
+            (
+                EnclaveError::InvalidInput("x".into()),
+                StatusCode::BAD_REQUEST,
+                "invalid_input",
+            ),
+            (
+                EnclaveError::UnsupportedSource("kitsu".into()),
+                StatusCode::BAD_REQUEST,
+                "unsupported_source",
+            ),
+            (
+                EnclaveError::NotFound("x".into()),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                EnclaveError::IncompleteRecord("x".into()),
+                StatusCode::BAD_GATEWAY,
+                "incomplete_record",
+            ),
+            (
+                EnclaveError::Ambiguous("x".into()),
+                StatusCode::CONFLICT,
+                "ambiguous",
+            ),
+            (
+                EnclaveError::UpstreamUnavailable("x".into()),
+                StatusCode::BAD_GATEWAY,
+                "upstream_unavailable",
+            ),
+            (
+                EnclaveError::UpstreamRateLimited("x".into()),
+                StatusCode::TOO_MANY_REQUESTS,
+                "upstream_rate_limited",
+            ),
+            (
+                EnclaveError::RateLimited("x".into()),
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+            ),
+            (
+                EnclaveError::CircuitOpen("x".into()),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "circuit_open",
+            ),
+            (
+                EnclaveError::Timeout("x".into()),
+                StatusCode::GATEWAY_TIMEOUT,
+                "timeout",
+            ),
+            (
+                EnclaveError::Misconfigured("x".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "misconfigured",
+            ),
+            (
+                EnclaveError::Internal("x".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!(error.status(), status);
+            assert_eq!(error.code(), code);
+        }
+    }
+
+    #[test]
+    fn test_upstream_status_mapping() {
+        let error =
+            EnclaveError::from_upstream_status("MAL", reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert!(matches!(error, EnclaveError::UpstreamRateLimited(_)));
+        assert!(error.is_retryable());
+        let error =
+            EnclaveError::from_upstream_status("MAL", reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(matches!(error, EnclaveError::UpstreamUnavailable(_)));
+        assert!(error.is_retryable());
+        let error = EnclaveError::from_upstream_status("MAL", reqwest::StatusCode::UNAUTHORIZED);
+        assert!(!error.is_retryable());
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-host token bucket and circuit breaker guarding upstream calls, so the
+//! enclave stays under provider rate limits and fails fast while a provider
+//! is down instead of waiting on every request for a network failure.
+
+use crate::allowlist::normalize_host;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Policy applied to one upstream host.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostPolicy {
+    /// Sustained request rate admitted to the host.
+    pub requests_per_second: f64,
+    /// Requests admitted at once after an idle period.
+    pub burst: u32,
+    /// Consecutive failures (connect errors, timeouts, 5xx) that open the breaker.
+    pub failure_threshold: u32,
+    /// How long an open breaker rejects requests before letting one probe through.
+    pub open_secs: u64,
+}
+
+impl Default for HostPolicy {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 10.0,
+            burst: 20,
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+/// Per-host policies, read from the optional `limits` section of
+/// allowed_endpoints.yaml:
+///
+/// ```yaml
+/// endpoints:
+///   - api.myanimelist.net
+/// limits:
+///   api.myanimelist.net:
+///     requests_per_second: 1
+///     burst: 3
+/// ```
+///
+/// Omitted fields and hosts without an entry use `HostPolicy::default()`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub limits: HashMap<String, HostPolicy>,
+}
+
+impl LimitsConfig {
+    /// Parse the `limits` section of an allowed_endpoints.yaml document.
+    pub fn from_yaml(yaml: &str) -> anyhow::Result<Self> {
+        if yaml.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let mut config: Self = serde_yaml::from_str(yaml)?;
+        config.limits = config
+            .limits
+            .into_iter()
+            .map(|(host, policy)| (normalize_host(&host), policy))
+            .collect();
+        for (host, policy) in &config.limits {
+            anyhow::ensure!(
+                policy.requests_per_second > 0.0 && policy.burst > 0,
+                "limits for {host}: requests_per_second and burst must be positive"
+            );
+            anyhow::ensure!(
+                policy.failure_threshold > 0,
+                "limits for {host}: failure_threshold must be positive"
+            );
+        }
+        Ok(config)
+    }
+
+    fn policy(&self, host: &str) -> HostPolicy {
+        self.limits.get(host).copied().unwrap_or_default()
+    }
+}
+
+/// Why a request to a host was not admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The host's token bucket is empty.
+    RateLimited,
+    /// The host's breaker is open for at least `retry_in`.
+    CircuitOpen { retry_in: Duration },
+}
+
+/// Breaker state reported by `health_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests flow normally.
+    Closed,
+    /// Requests are rejected until the open period ends.
+    Open,
+    /// The open period ended and a single probe request decides the state.
+    HalfOpen,
+}
+
+/// Breaker status of one upstream host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// Time until an open breaker lets a probe through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_in_ms: Option<u64>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(policy: &HostPolicy, now: Instant) -> Self {
+        Self {
+            tokens: policy.burst as f64,
+            capacity: policy.burst as f64,
+            refill_per_sec: policy.requests_per_second,
+            refilled_at: now,
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+enum Breaker {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// A probe was admitted at `since` and has not reported back yet.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+struct HostGuard {
+    bucket: TokenBucket,
+    breaker: Breaker,
+    consecutive_failures: u32,
+    policy: HostPolicy,
+}
+
+impl HostGuard {
+    fn admit(&mut self, now: Instant) -> Result<(), Rejection> {
+        let probe = match self.breaker {
+            Breaker::Closed => false,
+            Breaker::Open { until } if now < until => {
+                return Err(Rejection::CircuitOpen {
+                    retry_in: until - now,
+                })
+            }
+            // Only one probe at a time, the others keep failing fast. A
+            // probe that never reports back is replaced after the open period.
+            Breaker::HalfOpen { since } if now < since + self.open_for() => {
+                return Err(Rejection::CircuitOpen {
+                    retry_in: since + self.open_for() - now,
+                })
+            }
+            Breaker::Open { .. } | Breaker::HalfOpen { .. } => true,
+        };
+        if !self.bucket.try_take(now) {
+            return Err(Rejection::RateLimited);
+        }
+        if probe {
+            self.breaker = Breaker::HalfOpen { since: now };
+        }
+        Ok(())
+    }
+
+    fn open_for(&self) -> Duration {
+        Duration::from_secs(self.policy.open_secs)
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        if success {
+            self.consecutive_failures = 0;
+            self.breaker = Breaker::Closed;
+            return;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let failed_probe = matches!(self.breaker, Breaker::HalfOpen { .. });
+        if failed_probe || self.consecutive_failures >= self.policy.failure_threshold {
+            self.breaker = Breaker::Open {
+                until: now + self.open_for(),
+            };
+        }
+    }
+
+    fn status(&self, now: Instant) -> BreakerStatus {
+        let (state, retry_in) = match self.breaker {
+            Breaker::Closed => (BreakerState::Closed, None),
+            Breaker::Open { until } if now < until => (BreakerState::Open, Some(until - now)),
+            Breaker::Open { .. } | Breaker::HalfOpen { .. } => (BreakerState::HalfOpen, None),
+        };
+        BreakerStatus {
+            state,
+            consecutive_failures: self.consecutive_failures,
+            retry_in_ms: retry_in.map(|d| d.as_millis() as u64),
+        }
+    }
+}
+
+/// Token buckets and breakers of every upstream host contacted so far.
+#[derive(Default)]
+pub struct HostGuards {
+    config: LimitsConfig,
+    guards: Mutex<HashMap<String, HostGuard>>,
+}
+
+impl HostGuards {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            guards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admit one request to `host`, taking a token from its bucket.
+    pub fn admit(&self, host: &str, now: Instant) -> Result<(), Rejection> {
+        let mut guards = self.guards.lock().expect("host guards lock poisoned");
+        guards
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                let policy = self.config.policy(host);
+                HostGuard {
+                    bucket: TokenBucket::new(&policy, now),
+                    breaker: Breaker::Closed,
+                    consecutive_failures: 0,
+                    policy,
+                }
+            })
+            .admit(now)
+    }
+
+    /// Record the outcome of an admitted request to `host`.
+    pub fn record(&self, host: &str, success: bool, now: Instant) {
+        let mut guards = self.guards.lock().expect("host guards lock poisoned");
+        if let Some(guard) = guards.get_mut(host) {
+            guard.record(success, now);
+        }
+    }
+
+    /// Breaker status of every host contacted so far.
+    pub fn breakers(&self) -> BTreeMap<String, BreakerStatus> {
+        let now = Instant::now();
+        let guards = self.guards.lock().expect("host guards lock poisoned");
+        guards
+            .iter()
+            .map(|(host, guard)| (host.clone(), guard.status(now)))
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for HostGuards {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostGuards")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guards(policy: HostPolicy) -> HostGuards {
+        HostGuards::new(LimitsConfig {
+            limits: HashMap::from([("api.myanimelist.net".to_string(), policy)]),
+        })
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let guards = guards(HostPolicy {
+            requests_per_second: 2.0,
+            burst: 2,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let host = "api.myanimelist.net";
+        assert_eq!(guards.admit(host, start), Ok(()));
+        assert_eq!(guards.admit(host, start), Ok(()));
+        assert_eq!(guards.admit(host, start), Err(Rejection::RateLimited));
+        // Half a second refills one token at 2 requests per second.
+        assert_eq!(
+            guards.admit(host, start + Duration::from_millis(500)),
+            Ok(())
+        );
+        assert_eq!(
+            guards.admit(host, start + Duration::from_millis(500)),
+            Err(Rejection::RateLimited)
+        );
+        // Other hosts have their own bucket.
+        assert_eq!(guards.admit("graphql.anilist.co", start), Ok(()));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let guards = guards(HostPolicy {
+            failure_threshold: 2,
+            open_secs: 10,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let host = "api.myanimelist.net";
+        for _ in 0..2 {
+            guards.admit(host, start).unwrap();
+            guards.record(host, false, start);
+        }
+        assert_eq!(
+            guards.admit(host, start + Duration::from_secs(4)),
+            Err(Rejection::CircuitOpen {
+                retry_in: Duration::from_secs(6)
+            })
+        );
+        assert_eq!(guards.breakers()[host].state, BreakerState::Open);
+
+        // After the open period a single probe goes through; its failure
+        // reopens the breaker straight away.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(guards.admit(host, later), Ok(()));
+        assert!(matches!(
+            guards.admit(host, later),
+            Err(Rejection::CircuitOpen { .. })
+        ));
+        guards.record(host, false, later);
+        assert!(matches!(
+            guards.admit(host, later + Duration::from_secs(1)),
+            Err(Rejection::CircuitOpen { .. })
+        ));
+
+        // A successful probe closes it.
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(guards.admit(host, much_later), Ok(()));
+        guards.record(host, true, much_later);
+        assert_eq!(guards.admit(host, much_later), Ok(()));
+        let status = &guards.breakers()[host];
+        assert_eq!(status.state, BreakerState::Closed);
+        assert_eq!(status.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_limits_from_yaml() {
+        let yaml = "endpoints:\n  - api.myanimelist.net/\nlimits:\n  api.myanimelist.net/:\n    requests_per_second: 1\n    burst: 3\n";
+        let config = LimitsConfig::from_yaml(yaml).unwrap();
+        let policy = config.policy("api.myanimelist.net");
+        assert_eq!(policy.requests_per_second, 1.0);
+        assert_eq!(policy.burst, 3);
+        assert_eq!(
+            policy.failure_threshold,
+            HostPolicy::default().failure_threshold
+        );
+        assert_eq!(config.policy("graphql.anilist.co"), HostPolicy::default());
+
+        assert_eq!(
+            LimitsConfig::from_yaml("endpoints:\n  - api.mangadex.org\n").unwrap(),
+            LimitsConfig::default()
+        );
+        assert!(LimitsConfig::from_yaml("limits:\n  api.mangadex.org:\n    burst: 0\n").is_err());
+        assert!(LimitsConfig::from_yaml("limits:\n  api.mangadex.org:\n    rps: 1\n").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log output. Installs the global tracing subscriber, JSON by default, and
+//! the middleware that tags every request with an `x-request-id` and logs its
+//! outcome. Only the method and path of a request are logged, never its
+//! headers or query, and the HTTP client crates are kept at info so their
+//! debug output cannot leak the upstream credentials.
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+use tracing_subscriber::filter::Directive;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+/// Header carrying the request id, taken from the caller when valid.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest caller-supplied request id that is propagated.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Crates whose debug and trace output may include upstream request headers,
+/// and with them `MAL_CLIENT_ID` or the bearer token. They stay at info
+/// whatever `RUST_LOG` says.
+const CAPPED_DEPENDENCIES: &[&str] = &["hyper=info", "h2=info", "reqwest=info", "rustls=info"];
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON object per line, for log shipping.
+    Json,
+    /// Human readable lines, for local runs.
+    Text,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// `EnvFilter` directives, e.g. `info,nautilus_server=debug`.
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Json,
+            filter: "info".to_string(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// Read `LOG_FORMAT` (`json` or `text`) and `RUST_LOG`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        if let Ok(format) = std::env::var("LOG_FORMAT") {
+            config.format = match format.trim().to_lowercase().as_str() {
+                "json" => LogFormat::Json,
+                "text" => LogFormat::Text,
+                other => anyhow::bail!("LOG_FORMAT must be json or text, got {other:?}"),
+            };
+        }
+        if let Ok(filter) = std::env::var("RUST_LOG") {
+            config.filter = filter;
+        }
+        config.env_filter()?;
+        Ok(config)
+    }
+
+    fn env_filter(&self) -> anyhow::Result<EnvFilter> {
+        let mut filter = EnvFilter::try_new(&self.filter)
+            .map_err(|e| anyhow::anyhow!("invalid RUST_LOG {:?}: {e}", self.filter))?;
+        for directive in CAPPED_DEPENDENCIES {
+            filter = filter.add_directive(directive.parse::<Directive>()?);
+        }
+        Ok(filter)
+    }
+}
+
+/// Install the global subscriber. Fails if one is already installed.
+pub fn init(config: &LogConfig) -> anyhow::Result<()> {
+    let builder = tracing_subscriber::fmt().with_env_filter(config.env_filter()?);
+    let installed = match config.format {
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+        LogFormat::Text => builder.try_init(),
+    };
+    installed.map_err(|e| anyhow::anyhow!("cannot install log subscriber: {e}"))
+}
+
+/// Id of the request being handled on this task, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Middleware assigning each request an id, or keeping the caller's
+/// `x-request-id` if it is a plain token of up to 128 characters. The id is
+/// attached to every log line of the request, returned in the response header
+/// and in error bodies, and the method, path, status and latency are logged
+/// once the response is ready.
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+
+    let started = Instant::now();
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(request))
+        .instrument(span.clone())
+        .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            latency_ms, "request completed"
+        )
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::serve;
+    use crate::EnclaveError;
+    use axum::{middleware, routing::get, Router};
+
+    #[tokio::test]
+    async fn test_request_id_propagation() {
+        async fn missing() -> Result<&'static str, EnclaveError> {
+            Err(EnclaveError::NotFound("no such anime".to_string()))
+        }
+
+        let router = Router::new()
+            .route("/", get(|| async { "Pong!" }))
+            .route("/missing", get(missing))
+            .layer(middleware::from_fn(request_id));
+        let addr = serve(router).await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(format!("http://{addr}/missing"))
+            .header(REQUEST_ID_HEADER, "req-42")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], "req-42");
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["request_id"], "req-42");
+
+        // Ids that are not plain tokens are replaced.
+        let resp = client
+            .get(format!("http://{addr}/"))
+            .header(REQUEST_ID_HEADER, "bad id\twith spaces")
+            .send()
+            .await
+            .unwrap();
+        let id = resp.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+
+        // Outside a request there is no id.
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[test]
+    fn test_log_config_filter() {
+        assert!(LogConfig::default().env_filter().is_ok());
+        let config = LogConfig {
+            format: LogFormat::Json,
+            filter: "info,nautilus_server=debug".to_string(),
+        };
+        assert!(config.env_filter().is_ok());
+        let config = LogConfig {
+            format: LogFormat::Json,
+            filter: "info,nautilus_server=loud".to_string(),
+        };
+        assert!(config.env_filter().is_err());
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+}
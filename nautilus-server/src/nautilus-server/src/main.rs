@@ -2,39 +2,107 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
-use axum::{routing::get, routing::post, Router};
+use axum::extract::DefaultBodyLimit;
+use axum::{middleware, routing::get, routing::post, Router};
+use fastcrypto::encoding::{Encoding, Hex};
 use fastcrypto::{ed25519::Ed25519KeyPair, traits::KeyPair};
+use nautilus_server::allowlist::Allowlist;
 use nautilus_server::app::process_data;
-use nautilus_server::common::{get_attestation, health_check};
+use nautilus_server::attestation::verify;
+use nautilus_server::cache::{CacheConfig, ResponseCache};
+use nautilus_server::common::{
+    get_attestation, health_check, livez, post_attestation, readyz, GetAttestationResponse,
+};
+use nautilus_server::config::{request_timeout, ServerConfig};
+use nautilus_server::logging::{self, LogConfig};
+use nautilus_server::metrics;
+use nautilus_server::readiness::{Readiness, ReadinessConfig};
+use nautilus_server::upstream::{UpstreamClient, UpstreamConfig};
 use nautilus_server::AppState;
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let eph_kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("verify-attestation") {
+        return verify_attestation_cmd(&args[1..]);
+    }
+
+    logging::init(&LogConfig::from_env()?)?;
+    let server_config = ServerConfig::load()?;
+    info!("server config {:?}", server_config);
 
+    let eph_kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
 
     let api_key = std::env::var("API_KEY").unwrap_or_default();
 
-    let state = Arc::new(AppState { eph_kp, api_key });
+    let cache = Arc::new(ResponseCache::new(CacheConfig::from_env()?));
+    cache.spawn_sweeper();
+
+    let allowlist = Allowlist::embedded()?;
+    info!(
+        "egress allowlist {:?}, digest {}",
+        allowlist.hosts(),
+        Hex::encode(allowlist.digest())
+    );
+    let upstream_config = UpstreamConfig::from_env()?;
+    server_config.check_upstream_budget(&upstream_config)?;
+    let upstream = UpstreamClient::new(upstream_config, allowlist)?;
 
-    // The crate ships with a single integrated MyAnimeList handler by default.
-    // No host-only init server is spawned.
+    let readiness = Arc::new(Readiness::new(ReadinessConfig::from_env()?));
+    readiness.spawn_prober(upstream.clone());
 
-    // Define your own restricted CORS policy here if needed.
-    let cors = CorsLayer::new().allow_methods(Any).allow_headers(Any);
+    let state = Arc::new(AppState {
+        eph_kp,
+        api_key,
+        cache,
+        upstream,
+        readiness,
+    });
+
+    // `process_data` routes to every provider compiled in via the
+    // `myanimelist`, `anilist` and `mangadex` features. No host-only init
+    // server is spawned.
 
     let app = Router::new()
         .route("/", get(ping))
-        .route("/get_attestation", get(get_attestation))
+        .route(
+            "/get_attestation",
+            get(get_attestation).post(post_attestation),
+        )
         .route("/process_data", post(process_data))
         .route("/health_check", get(health_check))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics::metrics));
+
+    // Dev builds sign attestations with a local CA; expose its root so
+    // verifiers can be pointed at it instead of the AWS Nitro root.
+    #[cfg(feature = "dev-attestation")]
+    let app = {
+        tracing::warn!("dev-attestation is enabled, attestation documents are NOT hardware backed");
+        app.route(
+            "/mock_root_certificate",
+            get(nautilus_server::attestation::mock::get_mock_root_certificate),
+        )
+    };
+
+    let app = app
         .with_state(state)
-        .layer(cors);
+        .layer(DefaultBodyLimit::max(server_config.body_limit_bytes))
+        .layer(middleware::from_fn_with_state(
+            server_config.request_timeout(),
+            request_timeout,
+        ))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(logging::request_id))
+        .layer(server_config.cors_layer()?);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+    let listener = tokio::net::TcpListener::bind(server_config.listen_addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("cannot listen on {}: {e}", server_config.listen_addr))?;
     info!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app.into_make_service())
         .await
@@ -44,3 +112,56 @@ async fn main() -> Result<()> {
 async fn ping() -> &'static str {
     "Pong!"
 }
+
+const VERIFY_ATTESTATION_USAGE: &str = "usage: nautilus-server verify-attestation \
+--attestation <file|-> --root <root.pem|root.der|root.json> [--pcrs <nitro.pcrs>] [--pcr <index>=<hex>]...";
+
+/// `verify-attestation` subcommand: verify a document saved from
+/// `get_attestation` offline and print its verified contents as JSON.
+fn verify_attestation_cmd(args: &[String]) -> Result<()> {
+    let mut attestation = None;
+    let mut root = None;
+    let mut expected_pcrs = BTreeMap::new();
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next().ok_or_else(|| {
+                anyhow::anyhow!("{flag} requires a value\n{VERIFY_ATTESTATION_USAGE}")
+            })
+        };
+        match flag.as_str() {
+            "--attestation" => attestation = Some(value()?.clone()),
+            "--root" => root = Some(value()?.clone()),
+            "--pcrs" => expected_pcrs.extend(verify::parse_nitro_pcrs(&std::fs::read_to_string(
+                value()?,
+            )?)?),
+            "--pcr" => {
+                let (index, hex) = value()?
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("--pcr expects <index>=<hex>"))?;
+                expected_pcrs.insert(index.parse()?, hex.to_string());
+            }
+            _ => anyhow::bail!("unknown argument {flag}\n{VERIFY_ATTESTATION_USAGE}"),
+        }
+    }
+
+    let attestation = attestation.ok_or_else(|| anyhow::anyhow!(VERIFY_ATTESTATION_USAGE))?;
+    let root = root.ok_or_else(|| anyhow::anyhow!(VERIFY_ATTESTATION_USAGE))?;
+
+    let contents = if attestation == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(&attestation)?
+    };
+    // Accept either the raw hex or the JSON body returned by get_attestation.
+    let document = match serde_json::from_str::<GetAttestationResponse>(&contents) {
+        Ok(response) => response.attestation,
+        Err(_) => contents,
+    };
+
+    let verified = verify::verify_attestation(&document, &std::fs::read(&root)?)?;
+    verify::check_pcrs(&verified, &expected_pcrs)?;
+    println!("{}", serde_json::to_string_pretty(&verified)?);
+    Ok(())
+}
@@ -0,0 +1,421 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prometheus metrics served on `/metrics`. Request latency per route next to
+//! upstream latency per provider shows whether time goes to the enclave or to
+//! the provider behind the vsock proxy; connect errors and timeouts point at
+//! the proxy itself. Counters live in the process-wide `METRICS`; cache and
+//! breaker figures are read from `AppState` when scraped.
+
+use crate::cache::CacheStats;
+use crate::common::IntentScope;
+use crate::limiter::{BreakerState, BreakerStatus};
+use crate::probe::ProbeResult;
+use crate::AppState;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+lazy_static! {
+    /// Metrics of this process.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket of `LATENCY_BUCKETS`, not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Keyed by route, method and status.
+    requests: BTreeMap<(String, String, u16), u64>,
+    request_latency: BTreeMap<String, Histogram>,
+    /// Keyed by provider and outcome.
+    upstream_calls: BTreeMap<(String, &'static str), u64>,
+    upstream_latency: BTreeMap<String, Histogram>,
+    signatures: BTreeMap<String, u64>,
+    /// Latest result per host.
+    probes: BTreeMap<String, ProbeResult>,
+    /// Keyed by host and failure kind.
+    probe_failures: BTreeMap<(String, &'static str), u64>,
+}
+
+/// Counters and histograms of requests, upstream calls, signatures and
+/// health probes.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+impl Metrics {
+    fn registry(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.registry.lock().expect("metrics lock poisoned")
+    }
+
+    pub fn record_request(&self, route: &str, method: &str, status: u16, latency: Duration) {
+        let mut registry = self.registry();
+        *registry
+            .requests
+            .entry((route.to_string(), method.to_string(), status))
+            .or_default() += 1;
+        registry
+            .request_latency
+            .entry(route.to_string())
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Record one upstream attempt. `latency` is `None` for attempts the
+    /// limiter rejected before sending.
+    pub fn record_upstream(
+        &self,
+        provider: &str,
+        outcome: &'static str,
+        latency: Option<Duration>,
+    ) {
+        let mut registry = self.registry();
+        *registry
+            .upstream_calls
+            .entry((provider.to_string(), outcome))
+            .or_default() += 1;
+        if let Some(latency) = latency {
+            registry
+                .upstream_latency
+                .entry(provider.to_string())
+                .or_default()
+                .observe(latency);
+        }
+    }
+
+    pub fn record_signature(&self, scope: &IntentScope) {
+        *self
+            .registry()
+            .signatures
+            .entry(format!("{scope:?}"))
+            .or_default() += 1;
+    }
+
+    pub fn record_probe(&self, host: &str, result: &ProbeResult) {
+        let mut registry = self.registry();
+        if let Some(error) = &result.error {
+            *registry
+                .probe_failures
+                .entry((host.to_string(), error.kind.as_str()))
+                .or_default() += 1;
+        }
+        registry.probes.insert(host.to_string(), result.clone());
+    }
+
+    /// Render everything in the Prometheus text exposition format.
+    pub fn render(&self, cache: &CacheStats, breakers: &BTreeMap<String, BreakerStatus>) -> String {
+        let registry = self.registry();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "nautilus_http_requests_total",
+            "counter",
+            "Requests handled, by route, method and status.",
+        );
+        for ((route, method, status), count) in &registry.requests {
+            let _ = writeln!(
+                out,
+                "nautilus_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{status}\"}} {count}",
+                escape(route),
+                escape(method)
+            );
+        }
+        header(
+            &mut out,
+            "nautilus_http_request_duration_seconds",
+            "histogram",
+            "Time to respond, by route.",
+        );
+        for (route, histogram) in &registry.request_latency {
+            histogram.render(
+                &mut out,
+                "nautilus_http_request_duration_seconds",
+                &format!("route=\"{}\"", escape(route)),
+            );
+        }
+
+        header(
+            &mut out,
+            "nautilus_upstream_requests_total",
+            "counter",
+            "Upstream attempts, by provider and outcome.",
+        );
+        for ((provider, outcome), count) in &registry.upstream_calls {
+            let _ = writeln!(
+                out,
+                "nautilus_upstream_requests_total{{provider=\"{}\",outcome=\"{outcome}\"}} {count}",
+                escape(provider)
+            );
+        }
+        header(
+            &mut out,
+            "nautilus_upstream_request_duration_seconds",
+            "histogram",
+            "Time to upstream response headers, by provider.",
+        );
+        for (provider, histogram) in &registry.upstream_latency {
+            histogram.render(
+                &mut out,
+                "nautilus_upstream_request_duration_seconds",
+                &format!("provider=\"{}\"", escape(provider)),
+            );
+        }
+        header(
+            &mut out,
+            "nautilus_upstream_breaker_open",
+            "gauge",
+            "1 while the host's circuit breaker is open.",
+        );
+        for (host, status) in breakers {
+            let open = u8::from(status.state == BreakerState::Open);
+            let _ = writeln!(
+                out,
+                "nautilus_upstream_breaker_open{{host=\"{}\"}} {open}",
+                escape(host)
+            );
+        }
+
+        let counters = [
+            ("hits", cache.hits, "Cache lookups served from the cache."),
+            (
+                "misses",
+                cache.misses,
+                "Cache lookups that fetched upstream or joined a fetch.",
+            ),
+            (
+                "coalesced",
+                cache.coalesced,
+                "Misses served by another caller's fetch.",
+            ),
+            (
+                "evictions",
+                cache.evictions,
+                "Entries dropped to make room.",
+            ),
+            (
+                "expirations",
+                cache.expirations,
+                "Entries dropped after their TTL.",
+            ),
+            (
+                "refreshes",
+                cache.refreshes,
+                "Background refreshes of hot keys.",
+            ),
+        ];
+        for (name, value, help) in counters {
+            let metric = format!("nautilus_cache_{name}_total");
+            header(&mut out, &metric, "counter", help);
+            let _ = writeln!(out, "{metric} {value}");
+        }
+        header(
+            &mut out,
+            "nautilus_cache_entries",
+            "gauge",
+            "Entries in the response cache.",
+        );
+        let _ = writeln!(out, "nautilus_cache_entries {}", cache.entries);
+
+        header(
+            &mut out,
+            "nautilus_signatures_total",
+            "counter",
+            "Payloads signed, by intent scope.",
+        );
+        for (scope, count) in &registry.signatures {
+            let _ = writeln!(
+                out,
+                "nautilus_signatures_total{{scope=\"{}\"}} {count}",
+                escape(scope)
+            );
+        }
+
+        header(
+            &mut out,
+            "nautilus_probe_healthy",
+            "gauge",
+            "1 if the host passed its latest health probe.",
+        );
+        for (host, result) in &registry.probes {
+            let _ = writeln!(
+                out,
+                "nautilus_probe_healthy{{host=\"{}\"}} {}",
+                escape(host),
+                u8::from(result.healthy)
+            );
+        }
+        header(
+            &mut out,
+            "nautilus_probe_latency_seconds",
+            "gauge",
+            "Duration of the host's latest health probe.",
+        );
+        for (host, result) in &registry.probes {
+            let _ = writeln!(
+                out,
+                "nautilus_probe_latency_seconds{{host=\"{}\"}} {}",
+                escape(host),
+                result.latency_ms as f64 / 1000.0
+            );
+        }
+        header(
+            &mut out,
+            "nautilus_probe_failures_total",
+            "counter",
+            "Failed health probes, by host and reason.",
+        );
+        for ((host, kind), count) in &registry.probe_failures {
+            let _ = writeln!(
+                out,
+                "nautilus_probe_failures_total{{host=\"{}\",kind=\"{kind}\"}} {count}",
+                escape(host)
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Endpoint serving `METRICS` in the Prometheus text format.
+pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let body = METRICS.render(&state.cache.stats(), &state.upstream.breakers());
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Middleware counting requests and timing them per route template, so
+/// `/process_data` is one series whatever the request asks for. Requests
+/// matching no route are counted under `unmatched`.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    METRICS.record_request(
+        &route,
+        &method,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::{ProbeError, ProbeErrorKind};
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::default();
+        metrics.record_request("/process_data", "POST", 200, Duration::from_millis(40));
+        metrics.record_request("/process_data", "POST", 200, Duration::from_millis(700));
+        metrics.record_upstream("MAL", "ok", Some(Duration::from_millis(30)));
+        metrics.record_upstream("MAL", "rate_limited", None);
+        metrics.record_signature(&IntentScope::MyAnimeList);
+        metrics.record_probe(
+            "api.myanimelist.net",
+            &ProbeResult {
+                healthy: false,
+                latency_ms: 1500,
+                status: None,
+                error: Some(ProbeError::new(ProbeErrorKind::Dns, "dns error")),
+            },
+        );
+        let cache = CacheStats {
+            hits: 3,
+            misses: 2,
+            ..Default::default()
+        };
+        let breakers = BTreeMap::from([(
+            "api.myanimelist.net".to_string(),
+            BreakerStatus {
+                state: BreakerState::Open,
+                consecutive_failures: 5,
+                retry_in_ms: Some(1_000),
+            },
+        )]);
+
+        let text = metrics.render(&cache, &breakers);
+        for line in [
+            "# TYPE nautilus_http_requests_total counter",
+            "nautilus_http_requests_total{route=\"/process_data\",method=\"POST\",status=\"200\"} 2",
+            "nautilus_http_request_duration_seconds_bucket{route=\"/process_data\",le=\"0.05\"} 1",
+            "nautilus_http_request_duration_seconds_bucket{route=\"/process_data\",le=\"1\"} 2",
+            "nautilus_http_request_duration_seconds_count{route=\"/process_data\"} 2",
+            "nautilus_upstream_requests_total{provider=\"MAL\",outcome=\"ok\"} 1",
+            "nautilus_upstream_requests_total{provider=\"MAL\",outcome=\"rate_limited\"} 1",
+            "nautilus_upstream_request_duration_seconds_count{provider=\"MAL\"} 1",
+            "nautilus_upstream_breaker_open{host=\"api.myanimelist.net\"} 1",
+            "nautilus_cache_hits_total 3",
+            "nautilus_cache_misses_total 2",
+            "nautilus_signatures_total{scope=\"MyAnimeList\"} 1",
+            "nautilus_probe_healthy{host=\"api.myanimelist.net\"} 0",
+            "nautilus_probe_latency_seconds{host=\"api.myanimelist.net\"} 1.5",
+            "nautilus_probe_failures_total{host=\"api.myanimelist.net\",kind=\"dns\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line} in\n{text}");
+        }
+    }
+
+    #[test]
+    fn test_escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Declarative health probes. Each endpoint in allowed_endpoints.yaml may
+//! declare how it is checked; `health_check` runs every probe concurrently
+//! and reports per host the status, latency and, on failure, why it failed.
+
+use crate::metrics::METRICS;
+use crate::upstream::UpstreamClient;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// Default time a probe may take, including reading the body.
+pub const DEFAULT_PROBE_TIMEOUT_MS: u64 = 5_000;
+
+/// HTTP method of a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ProbeMethod {
+    #[default]
+    Get,
+    Head,
+}
+
+impl From<ProbeMethod> for reqwest::Method {
+    fn from(method: ProbeMethod) -> Self {
+        match method {
+            ProbeMethod::Get => reqwest::Method::GET,
+            ProbeMethod::Head => reqwest::Method::HEAD,
+        }
+    }
+}
+
+/// How to check one endpoint, set under `probe` of its entry in
+/// allowed_endpoints.yaml:
+///
+/// ```yaml
+/// endpoints:
+///   - api.mangadex.org
+///   - host: kms.us-east-1.amazonaws.com
+///     probe:
+///       path: /ping
+///       body_contains: healthy
+/// ```
+///
+/// Plain entries and omitted fields request `GET /` and expect any 2xx, which
+/// few API roots answer, so every provider host declares its own probe.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeSpec {
+    /// Path requested on the host, starting with `/`.
+    pub path: String,
+    pub method: ProbeMethod,
+    /// Status the host must answer with. Any 2xx when unset.
+    pub expected_status: Option<u16>,
+    /// Case-insensitive text the response body must contain.
+    pub body_contains: Option<String>,
+    pub timeout_ms: u64,
+}
+
+impl Default for ProbeSpec {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            method: ProbeMethod::default(),
+            expected_status: None,
+            body_contains: None,
+            timeout_ms: DEFAULT_PROBE_TIMEOUT_MS,
+        }
+    }
+}
+
+impl ProbeSpec {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.path.starts_with('/'), "probe path must start with '/'");
+        anyhow::ensure!(
+            self.expected_status
+                .is_none_or(|status| (100..=599).contains(&status)),
+            "probe expected_status must be an HTTP status"
+        );
+        anyhow::ensure!(
+            self.method != ProbeMethod::Head || self.body_contains.is_none(),
+            "probe body_contains needs a GET probe"
+        );
+        anyhow::ensure!(self.timeout_ms > 0, "probe timeout_ms must be positive");
+        Ok(())
+    }
+}
+
+/// Why a probe failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeErrorKind {
+    /// The host name did not resolve.
+    Dns,
+    /// The connection was refused or reset, or the TLS handshake failed.
+    Connect,
+    /// No complete response within the probe's `timeout_ms`.
+    Timeout,
+    /// The host answered with another status than expected.
+    UnexpectedStatus,
+    /// The response body lacks the probe's `body_contains`.
+    BodyMismatch,
+    /// The host is not in the egress allowlist.
+    Denied,
+    /// Any other request failure, e.g. a broken response body.
+    Request,
+}
+
+impl ProbeErrorKind {
+    /// The serialized name, e.g. `unexpected_status`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeErrorKind::Dns => "dns",
+            ProbeErrorKind::Connect => "connect",
+            ProbeErrorKind::Timeout => "timeout",
+            ProbeErrorKind::UnexpectedStatus => "unexpected_status",
+            ProbeErrorKind::BodyMismatch => "body_mismatch",
+            ProbeErrorKind::Denied => "denied",
+            ProbeErrorKind::Request => "request",
+        }
+    }
+}
+
+/// Failure reason of a probe.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeError {
+    pub kind: ProbeErrorKind,
+    pub message: String,
+}
+
+impl ProbeError {
+    pub fn new(kind: ProbeErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Classify a request failure, telling resolver errors apart from other
+    /// connect errors through the error's source chain.
+    pub fn from_reqwest(error: &reqwest::Error) -> Self {
+        let mut message = error.to_string();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        let kind = if error.is_timeout() {
+            ProbeErrorKind::Timeout
+        } else if error.is_connect()
+            && (message.contains("dns error") || message.contains("failed to lookup address"))
+        {
+            ProbeErrorKind::Dns
+        } else if error.is_connect() {
+            ProbeErrorKind::Connect
+        } else {
+            ProbeErrorKind::Request
+        };
+        Self::new(kind, message)
+    }
+}
+
+/// Outcome of one probe, reported per host by `health_check`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub healthy: bool,
+    /// Time until the probe passed or failed.
+    pub latency_ms: u64,
+    /// Status the host answered with, if it answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ProbeError>,
+}
+
+/// Probe every allowlisted host over HTTPS concurrently.
+pub async fn run_probes(upstream: &UpstreamClient) -> BTreeMap<String, ProbeResult> {
+    let mut probes = JoinSet::new();
+    for (host, spec) in upstream.allowlist().probes() {
+        let upstream = upstream.clone();
+        let (host, spec) = (host.clone(), spec.clone());
+        probes.spawn(async move {
+            let result = match reqwest::Url::parse(&format!("https://{host}")) {
+                Ok(base) => probe(&upstream, &base, &spec).await,
+                Err(e) => ProbeResult {
+                    healthy: false,
+                    latency_ms: 0,
+                    status: None,
+                    error: Some(ProbeError::new(
+                        ProbeErrorKind::Request,
+                        format!("invalid host: {e}"),
+                    )),
+                },
+            };
+            (host, result)
+        });
+    }
+
+    let mut results = BTreeMap::new();
+    while let Some(joined) = probes.join_next().await {
+        match joined {
+            Ok((host, result)) => {
+                match &result.error {
+                    None => info!("Probed {}: healthy in {}ms", host, result.latency_ms),
+                    Some(e) => info!(
+                        "Probed {}: {:?} after {}ms: {}",
+                        host, e.kind, result.latency_ms, e.message
+                    ),
+                }
+                METRICS.record_probe(&host, &result);
+                results.insert(host, result);
+            }
+            Err(e) => warn!("Health probe task failed: {}", e),
+        }
+    }
+    results
+}
+
+/// Run `spec` against the host of `base`, once and without rate limiting.
+pub async fn probe(
+    upstream: &UpstreamClient,
+    base: &reqwest::Url,
+    spec: &ProbeSpec,
+) -> ProbeResult {
+    let started = Instant::now();
+    let outcome = check(upstream, base, spec).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match outcome {
+        Ok(status) => ProbeResult {
+            healthy: true,
+            latency_ms,
+            status: Some(status),
+            error: None,
+        },
+        Err((status, error)) => ProbeResult {
+            healthy: false,
+            latency_ms,
+            status,
+            error: Some(error),
+        },
+    }
+}
+
+/// The response status on success, otherwise the status, if any, and the
+/// failure reason.
+async fn check(
+    upstream: &UpstreamClient,
+    base: &reqwest::Url,
+    spec: &ProbeSpec,
+) -> Result<u16, (Option<u16>, ProbeError)> {
+    let url = base.join(&spec.path).map_err(|e| {
+        (
+            None,
+            ProbeError::new(ProbeErrorKind::Request, format!("invalid probe path: {e}")),
+        )
+    })?;
+    let request = upstream
+        .request(spec.method.into(), url)
+        .timeout(Duration::from_millis(spec.timeout_ms));
+    let response = upstream.probe(request).await.map_err(|e| (None, e))?;
+
+    let status = response.status();
+    let expected = match spec.expected_status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    };
+    if !expected {
+        let wanted = spec
+            .expected_status
+            .map_or("2xx".to_string(), |s| s.to_string());
+        return Err((
+            Some(status.as_u16()),
+            ProbeError::new(
+                ProbeErrorKind::UnexpectedStatus,
+                format!("expected {wanted}, got {status}"),
+            ),
+        ));
+    }
+
+    if let Some(needle) = &spec.body_contains {
+        let body = response
+            .text()
+            .await
+            .map_err(|e| (Some(status.as_u16()), ProbeError::from_reqwest(&e)))?;
+        if !body.to_lowercase().contains(&needle.to_lowercase()) {
+            return Err((
+                Some(status.as_u16()),
+                ProbeError::new(
+                    ProbeErrorKind::BodyMismatch,
+                    format!("body does not contain {needle:?}"),
+                ),
+            ));
+        }
+    }
+    Ok(status.as_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::serve;
+    use axum::http::StatusCode;
+    use axum::{routing::get, Router};
+
+    #[tokio::test]
+    async fn test_probe_reports_reason() {
+        let router = Router::new()
+            .route("/ping", get(|| async { "Healthy" }))
+            .route("/forbidden", get(|| async { StatusCode::FORBIDDEN }));
+        let addr = serve(router).await;
+        let upstream = UpstreamClient::for_tests();
+        let base = reqwest::Url::parse(&format!("http://{addr}")).unwrap();
+        let spec = |path: &str| ProbeSpec {
+            path: path.to_string(),
+            ..Default::default()
+        };
+
+        let result = probe(
+            &upstream,
+            &base,
+            &ProbeSpec {
+                body_contains: Some("healthy".to_string()),
+                ..spec("/ping")
+            },
+        )
+        .await;
+        assert!(result.healthy);
+        assert_eq!(result.status, Some(200));
+        assert_eq!(result.error, None);
+
+        let result = probe(
+            &upstream,
+            &base,
+            &ProbeSpec {
+                body_contains: Some("ready".to_string()),
+                ..spec("/ping")
+            },
+        )
+        .await;
+        assert_eq!(result.error.unwrap().kind, ProbeErrorKind::BodyMismatch);
+
+        let result = probe(&upstream, &base, &spec("/forbidden")).await;
+        assert!(!result.healthy);
+        assert_eq!(result.status, Some(403));
+        assert_eq!(result.error.unwrap().kind, ProbeErrorKind::UnexpectedStatus);
+
+        // A 403 is healthy when the probe expects it.
+        let result = probe(
+            &upstream,
+            &base,
+            &ProbeSpec {
+                expected_status: Some(403),
+                ..spec("/forbidden")
+            },
+        )
+        .await;
+        assert!(result.healthy);
+
+        // Nothing listens on a dropped listener's port.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let closed = reqwest::Url::parse(&format!("http://{closed}")).unwrap();
+        let result = probe(&upstream, &closed, &spec("/")).await;
+        assert_eq!(result.status, None);
+        assert_eq!(result.error.unwrap().kind, ProbeErrorKind::Connect);
+
+        let outside = reqwest::Url::parse("https://api.example.com").unwrap();
+        let result = probe(&upstream, &outside, &spec("/")).await;
+        assert_eq!(result.error.unwrap().kind, ProbeErrorKind::Denied);
+    }
+
+    #[test]
+    fn test_probe_spec_validation() {
+        assert!(ProbeSpec::default().validate().is_ok());
+        let invalid = [
+            ProbeSpec {
+                path: "ping".to_string(),
+                ..Default::default()
+            },
+            ProbeSpec {
+                expected_status: Some(42),
+                ..Default::default()
+            },
+            ProbeSpec {
+                method: ProbeMethod::Head,
+                body_contains: Some("ok".to_string()),
+                ..Default::default()
+            },
+            ProbeSpec {
+                timeout_ms: 0,
+                ..Default::default()
+            },
+        ];
+        for spec in invalid {
+            assert!(spec.validate().is_err(), "{spec:?}");
+        }
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Readiness state for `/readyz`. A background prober runs the health probes
+//! on an interval and caches the verdict, so load balancers can poll
+//! readiness without fanning out to every upstream on each request.
+
+use crate::cache::current_millis;
+use crate::common::env_u64;
+use crate::limiter::{BreakerState, BreakerStatus};
+use crate::probe::{run_probes, ProbeResult};
+use crate::upstream::UpstreamClient;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Default period of the background readiness probe.
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Probe results older than this many intervals no longer count, so a stuck
+/// prober turns the enclave unready instead of freezing the last verdict.
+const STALE_AFTER_INTERVALS: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct ReadinessConfig {
+    pub probe_interval: Duration,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: DEFAULT_PROBE_INTERVAL,
+        }
+    }
+}
+
+impl ReadinessConfig {
+    /// Read `READINESS_PROBE_INTERVAL_SECS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        if let Some(secs) = env_u64("READINESS_PROBE_INTERVAL_SECS")? {
+            config.probe_interval = Duration::from_secs(secs);
+        }
+        anyhow::ensure!(
+            !config.probe_interval.is_zero(),
+            "READINESS_PROBE_INTERVAL_SECS must be positive"
+        );
+        Ok(config)
+    }
+}
+
+/// Readiness verdict returned by `/readyz`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    /// When the probes behind this verdict ran, 0 before the first run.
+    pub checked_at_ms: u64,
+    /// Hosts that passed their probe and whose circuit breaker is not open.
+    pub available: Vec<String>,
+    /// Why the enclave is not ready, empty when it is.
+    pub reasons: Vec<String>,
+}
+
+impl ReadinessReport {
+    /// Ready when at least one allowlisted host passed its probe and its
+    /// breaker is not open. The signing key and configuration are loaded
+    /// before the server starts, so they hold whenever this is evaluated.
+    pub fn evaluate(
+        probes: &BTreeMap<String, ProbeResult>,
+        breakers: &BTreeMap<String, BreakerStatus>,
+        checked_at_ms: u64,
+    ) -> Self {
+        let mut available = Vec::new();
+        let mut reasons = Vec::new();
+        for (host, result) in probes {
+            let breaker_open = breakers
+                .get(host)
+                .is_some_and(|status| status.state == BreakerState::Open);
+            match &result.error {
+                Some(e) => reasons.push(format!("{host}: {:?}: {}", e.kind, e.message)),
+                None if breaker_open => reasons.push(format!("{host}: circuit breaker is open")),
+                None => available.push(host.clone()),
+            }
+        }
+        if probes.is_empty() {
+            reasons.push("no upstream providers are configured".to_string());
+        }
+        let ready = !available.is_empty();
+        if ready {
+            reasons.clear();
+        }
+        Self {
+            ready,
+            checked_at_ms,
+            available,
+            reasons,
+        }
+    }
+}
+
+/// Latest readiness verdict and the probe results behind it, shared between
+/// the prober, `/readyz` and `health_check`.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    config: ReadinessConfig,
+    report: RwLock<Option<ReadinessReport>>,
+    probes: RwLock<BTreeMap<String, ProbeResult>>,
+}
+
+impl Readiness {
+    pub fn new(config: ReadinessConfig) -> Self {
+        Self {
+            config,
+            report: RwLock::new(None),
+            probes: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Per-host results of the last probe run, empty before the first.
+    pub fn probes(&self) -> BTreeMap<String, ProbeResult> {
+        self.probes.read().expect("readiness lock poisoned").clone()
+    }
+
+    /// Store the results of a probe run and the verdict they lead to.
+    pub fn record(
+        &self,
+        probes: BTreeMap<String, ProbeResult>,
+        breakers: &BTreeMap<String, BreakerStatus>,
+        checked_at_ms: u64,
+    ) {
+        self.update(ReadinessReport::evaluate(&probes, breakers, checked_at_ms));
+        *self.probes.write().expect("readiness lock poisoned") = probes;
+    }
+
+    /// The cached verdict, unready before the first probe or once stale.
+    pub fn report(&self) -> ReadinessReport {
+        self.report_at(current_millis())
+    }
+
+    fn report_at(&self, now_ms: u64) -> ReadinessReport {
+        let report = self.report.read().expect("readiness lock poisoned").clone();
+        let Some(report) = report else {
+            return ReadinessReport {
+                ready: false,
+                checked_at_ms: 0,
+                available: Vec::new(),
+                reasons: vec!["upstreams have not been probed yet".to_string()],
+            };
+        };
+        let stale_after = (self.config.probe_interval * STALE_AFTER_INTERVALS).as_millis() as u64;
+        if now_ms.saturating_sub(report.checked_at_ms) > stale_after {
+            return ReadinessReport {
+                ready: false,
+                reasons: vec![format!("last probe at {} is stale", report.checked_at_ms)],
+                ..report
+            };
+        }
+        report
+    }
+
+    /// Replace the cached verdict, logging transitions.
+    pub fn update(&self, report: ReadinessReport) {
+        let mut current = self.report.write().expect("readiness lock poisoned");
+        let was_ready = current.as_ref().is_some_and(|r| r.ready);
+        if report.ready && !was_ready {
+            info!(
+                "enclave is ready, available upstreams {:?}",
+                report.available
+            );
+        } else if !report.ready && (was_ready || current.is_none()) {
+            warn!("enclave is not ready: {:?}", report.reasons);
+        }
+        *current = Some(report);
+    }
+
+    /// Probe the upstreams every `probe_interval`, starting now, for as long
+    /// as the runtime lives.
+    pub fn spawn_prober(self: &Arc<Self>, upstream: UpstreamClient) -> tokio::task::JoinHandle<()> {
+        let readiness = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(readiness.config.probe_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let probes = run_probes(&upstream).await;
+                readiness.record(probes, &upstream.breakers(), current_millis());
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::{ProbeError, ProbeErrorKind};
+
+    fn healthy() -> ProbeResult {
+        ProbeResult {
+            healthy: true,
+            latency_ms: 12,
+            status: Some(200),
+            error: None,
+        }
+    }
+
+    fn failed(kind: ProbeErrorKind) -> ProbeResult {
+        ProbeResult {
+            healthy: false,
+            latency_ms: 12,
+            status: None,
+            error: Some(ProbeError::new(kind, "failed")),
+        }
+    }
+
+    fn open() -> BreakerStatus {
+        BreakerStatus {
+            state: BreakerState::Open,
+            consecutive_failures: 5,
+            retry_in_ms: Some(1_000),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_readiness() {
+        let probes = BTreeMap::from([
+            ("api.myanimelist.net".to_string(), healthy()),
+            (
+                "graphql.anilist.co".to_string(),
+                failed(ProbeErrorKind::Dns),
+            ),
+        ]);
+        let report = ReadinessReport::evaluate(&probes, &BTreeMap::new(), 1);
+        assert!(report.ready);
+        assert_eq!(report.available, vec!["api.myanimelist.net"]);
+        assert!(report.reasons.is_empty());
+
+        // A reachable host behind an open breaker does not count.
+        let breakers = BTreeMap::from([("api.myanimelist.net".to_string(), open())]);
+        let report = ReadinessReport::evaluate(&probes, &breakers, 1);
+        assert!(!report.ready);
+        assert_eq!(report.reasons.len(), 2);
+
+        assert!(!ReadinessReport::evaluate(&BTreeMap::new(), &BTreeMap::new(), 1).ready);
+    }
+
+    #[test]
+    fn test_report_is_cached_until_stale() {
+        let readiness = Readiness::new(ReadinessConfig {
+            probe_interval: Duration::from_secs(10),
+        });
+        assert!(!readiness.report_at(1_000).ready);
+
+        let probes = BTreeMap::from([("api.mangadex.org".to_string(), healthy())]);
+        readiness.update(ReadinessReport::evaluate(&probes, &BTreeMap::new(), 1_000));
+        assert!(readiness.report_at(1_000).ready);
+        assert!(readiness.report_at(31_000).ready);
+        let stale = readiness.report_at(31_001);
+        assert!(!stale.ready);
+        assert_eq!(stale.available, vec!["api.mangadex.org"]);
+    }
+
+    #[test]
+    fn test_record_keeps_probe_results() {
+        let readiness = Readiness::default();
+        assert!(readiness.probes().is_empty());
+
+        let probes = BTreeMap::from([
+            ("api.mangadex.org".to_string(), healthy()),
+            (
+                "graphql.anilist.co".to_string(),
+                failed(ProbeErrorKind::Timeout),
+            ),
+        ]);
+        readiness.record(probes.clone(), &BTreeMap::new(), current_millis());
+        assert_eq!(readiness.probes(), probes);
+        assert_eq!(readiness.report().available, vec!["api.mangadex.org"]);
+    }
+}
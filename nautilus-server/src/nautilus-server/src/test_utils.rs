@@ -0,0 +1,39 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by the unit tests.
+
+use crate::upstream::UpstreamClient;
+use crate::AppState;
+use axum::Router;
+use fastcrypto::ed25519::Ed25519KeyPair;
+use fastcrypto::traits::KeyPair;
+use std::net::SocketAddr;
+
+/// Serve `router` on an ephemeral loopback port and return its address.
+pub async fn serve(router: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    addr
+}
+
+/// App state with a fresh key pair and empty cache, using `upstream`.
+pub fn app_state(upstream: UpstreamClient) -> AppState {
+    AppState {
+        eph_kp: Ed25519KeyPair::generate(&mut rand::thread_rng()),
+        api_key: "".to_string(),
+        cache: Default::default(),
+        upstream,
+        readiness: Default::default(),
+    }
+}
+
+/// App state whose provider requests are all served by `router`, standing in
+/// for the provider API under test.
+pub async fn mock_provider(router: Router) -> AppState {
+    let addr = serve(router).await;
+    app_state(UpstreamClient::for_tests().with_api_base(format!("http://{addr}")))
+}
@@ -0,0 +1,469 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Shared HTTP client for upstream provider APIs. One pooled client is held
+//! in `AppState`, so requests reuse connections (and TLS sessions through the
+//! vsock proxy) instead of handshaking on every call. Every request must be
+//! HTTPS to a host in the `Allowlist`, and every attempt passes the per-host
+//! rate limiter and circuit breaker in `limiter`.
+
+use crate::allowlist::Allowlist;
+use crate::common::env_u64;
+use crate::limiter::{BreakerStatus, HostGuards, Rejection};
+use crate::metrics::METRICS;
+use crate::probe::{ProbeError, ProbeErrorKind};
+use crate::EnclaveError;
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, RequestBuilder, Response};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Default time allowed to establish a connection.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Default time allowed for a whole request, including reading the body.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of retries after the first attempt.
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// Default backoff before the first retry, doubled for each further retry.
+pub const DEFAULT_RETRY_BASE: Duration = Duration::from_millis(200);
+
+/// Default upper bound of a single backoff, including `Retry-After`.
+pub const DEFAULT_RETRY_MAX: Duration = Duration::from_secs(5);
+
+/// Timeouts and retry policy of the upstream client.
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub max_retries: u32,
+    pub retry_base: Duration,
+    /// A `Retry-After` longer than this is not waited for; the response is
+    /// returned to the caller instead.
+    pub retry_max: Duration,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_base: DEFAULT_RETRY_BASE,
+            retry_max: DEFAULT_RETRY_MAX,
+        }
+    }
+}
+
+impl UpstreamConfig {
+    /// Read `UPSTREAM_CONNECT_TIMEOUT_MS`, `UPSTREAM_REQUEST_TIMEOUT_MS`,
+    /// `UPSTREAM_MAX_RETRIES`, `UPSTREAM_RETRY_BASE_MS` and
+    /// `UPSTREAM_RETRY_MAX_MS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        if let Some(ms) = env_u64("UPSTREAM_CONNECT_TIMEOUT_MS")? {
+            config.connect_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_u64("UPSTREAM_REQUEST_TIMEOUT_MS")? {
+            config.request_timeout = Duration::from_millis(ms);
+        }
+        if let Some(retries) = env_u64("UPSTREAM_MAX_RETRIES")? {
+            config.max_retries = retries as u32;
+        }
+        if let Some(ms) = env_u64("UPSTREAM_RETRY_BASE_MS")? {
+            config.retry_base = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_u64("UPSTREAM_RETRY_MAX_MS")? {
+            config.retry_max = Duration::from_millis(ms);
+        }
+        anyhow::ensure!(
+            !config.connect_timeout.is_zero(),
+            "UPSTREAM_CONNECT_TIMEOUT_MS must be positive"
+        );
+        anyhow::ensure!(
+            !config.request_timeout.is_zero(),
+            "UPSTREAM_REQUEST_TIMEOUT_MS must be positive"
+        );
+        Ok(config)
+    }
+
+    /// Longest time `UpstreamClient::send` can take: every attempt running
+    /// into `request_timeout`, with the longest wait before each retry.
+    pub fn worst_case(&self) -> Duration {
+        let attempts = self.max_retries.saturating_add(1);
+        self.request_timeout
+            .saturating_mul(attempts)
+            .saturating_add(self.retry_max.saturating_mul(self.max_retries))
+    }
+
+    /// Backoff before retry number `retry` (1-based): a random duration up to
+    /// `retry_base * 2^(retry - 1)`, capped at `retry_max`.
+    fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .retry_base
+            .saturating_mul(1 << (retry - 1).min(16))
+            .min(self.retry_max);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64))
+    }
+}
+
+/// Pooled HTTP client restricted to the egress allowlist, with timeouts,
+/// bounded jittered retries and per-host limits. Cheap to clone; clones share
+/// the connection pool and limits.
+#[derive(Debug, Clone)]
+pub struct UpstreamClient {
+    client: Client,
+    config: UpstreamConfig,
+    allowlist: Arc<Allowlist>,
+    guards: Arc<HostGuards>,
+    /// Mock server standing in for every provider API in tests.
+    #[cfg(test)]
+    api_base: Option<String>,
+}
+
+impl UpstreamClient {
+    pub fn new(config: UpstreamConfig, allowlist: Allowlist) -> anyhow::Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()?;
+        Ok(Self {
+            client,
+            config,
+            guards: Arc::new(HostGuards::new(allowlist.limits.clone())),
+            allowlist: Arc::new(allowlist),
+            #[cfg(test)]
+            api_base: None,
+        })
+    }
+
+    /// Client for tests against mock servers on the loopback address.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        Self::new(
+            UpstreamConfig::default(),
+            Allowlist::from_hosts(["127.0.0.1"]),
+        )
+        .expect("test upstream client")
+    }
+
+    /// Hosts this client may contact.
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+
+    /// Circuit breaker status of every upstream host contacted so far.
+    pub fn breakers(&self) -> BTreeMap<String, BreakerStatus> {
+        self.guards.breakers()
+    }
+
+    /// Send every provider request to `base`, e.g. `http://127.0.0.1:4000`.
+    #[cfg(test)]
+    pub(crate) fn with_api_base(mut self, base: String) -> Self {
+        self.api_base = Some(base);
+        self
+    }
+
+    /// Root URL of a provider API. It is compiled in rather than read from
+    /// the environment, which the host controls; tests replace it with their
+    /// mock server.
+    pub fn api_base(&self, default: &str) -> String {
+        #[cfg(test)]
+        if let Some(base) = &self.api_base {
+            return base.clone();
+        }
+        default.to_string()
+    }
+
+    /// Send `request` once, without retries or rate limiting, for health
+    /// probes. The allowlist still applies.
+    pub async fn probe(&self, request: RequestBuilder) -> Result<Response, ProbeError> {
+        let request = request.build().map_err(|e| ProbeError::from_reqwest(&e))?;
+        self.allowed_host(&request)
+            .map_err(|e| ProbeError::new(ProbeErrorKind::Denied, e.to_string()))?;
+        self.client
+            .execute(request)
+            .await
+            .map_err(|e| ProbeError::from_reqwest(&e))
+    }
+
+    fn allowed_host(&self, request: &reqwest::Request) -> Result<String, EnclaveError> {
+        let url = request.url();
+        if !self.allowlist.allows_url(url) {
+            return Err(EnclaveError::Misconfigured(format!(
+                "egress to {} is not permitted, only https to hosts in allowed_endpoints.yaml",
+                url.origin().ascii_serialization()
+            )));
+        }
+        Ok(url.host_str().unwrap_or_default().to_lowercase())
+    }
+
+    pub fn get(&self, url: reqwest::Url) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: reqwest::Url) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn request(&self, method: reqwest::Method, url: reqwest::Url) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    /// Send `request` to `provider`, retrying connect errors and 5xx
+    /// responses up to `max_retries` times. A `Retry-After` on a 5xx response
+    /// replaces the jittered backoff. Any other response, including the last
+    /// 5xx, is returned for the caller to map. Attempts the host's rate limit
+    /// or open breaker rejects fail with `RateLimited` or `CircuitOpen`.
+    pub async fn send(
+        &self,
+        provider: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, EnclaveError> {
+        let mut retry = 0;
+        loop {
+            let attempt = request
+                .try_clone()
+                .ok_or_else(|| {
+                    EnclaveError::Internal(format!("{provider} request cannot be retried"))
+                })?
+                .build()
+                .map_err(|e| EnclaveError::Internal(format!("invalid {provider} request: {e}")))?;
+            let host = self.allowed_host(&attempt)?;
+            if let Err(rejection) = self.guards.admit(&host, Instant::now()) {
+                let label = match rejection {
+                    Rejection::RateLimited => "rate_limited",
+                    Rejection::CircuitOpen { .. } => "circuit_open",
+                };
+                METRICS.record_upstream(provider, label, None);
+                return Err(rejection_error(provider, &host, rejection));
+            }
+
+            let started = Instant::now();
+            let outcome = self.client.execute(attempt).await;
+            METRICS.record_upstream(provider, outcome_label(&outcome), Some(started.elapsed()));
+            let healthy = matches!(&outcome, Ok(resp) if !resp.status().is_server_error());
+            self.guards.record(&host, healthy, Instant::now());
+
+            let retries_left = retry < self.config.max_retries;
+            let delay = match outcome {
+                Ok(resp) if retries_left && resp.status().is_server_error() => {
+                    match retry_after(&resp) {
+                        Some(delay) if delay > self.config.retry_max => return Ok(resp),
+                        Some(delay) => delay,
+                        None => self.config.backoff(retry + 1),
+                    }
+                }
+                Ok(resp) => return Ok(resp),
+                Err(e) if retries_left && e.is_connect() => self.config.backoff(retry + 1),
+                Err(e) => {
+                    return Err(EnclaveError::UpstreamUnavailable(format!(
+                        "Failed to request {provider}: {e}"
+                    )))
+                }
+            };
+            retry += 1;
+            debug!(
+                "retrying {} request in {:?} (retry {})",
+                provider, delay, retry
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Send `request` with retries and parse a successful JSON body.
+    pub async fn get_json(
+        &self,
+        provider: &str,
+        request: RequestBuilder,
+    ) -> Result<serde_json::Value, EnclaveError> {
+        let resp = self.send(provider, request).await?;
+        if !resp.status().is_success() {
+            return Err(EnclaveError::from_upstream_status(provider, resp.status()));
+        }
+        resp.json().await.map_err(|e| {
+            EnclaveError::UpstreamUnavailable(format!("Failed to parse {provider} JSON: {e}"))
+        })
+    }
+}
+
+fn rejection_error(provider: &str, host: &str, rejection: Rejection) -> EnclaveError {
+    match rejection {
+        Rejection::RateLimited => EnclaveError::RateLimited(format!(
+            "request rate limit for {provider} ({host}) reached"
+        )),
+        Rejection::CircuitOpen { retry_in } => EnclaveError::CircuitOpen(format!(
+            "{provider} ({host}) is failing, retry in {}s",
+            retry_in.as_secs().max(1)
+        )),
+    }
+}
+
+/// Outcome of one attempt, as reported on `/metrics`.
+fn outcome_label(outcome: &Result<Response, reqwest::Error>) -> &'static str {
+    match outcome {
+        Ok(resp) if resp.status().is_success() => "ok",
+        Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => "throttled",
+        Ok(resp) if resp.status().is_server_error() => "server_error",
+        Ok(_) => "client_error",
+        Err(e) if e.is_timeout() => "timeout",
+        Err(e) if e.is_connect() => "connect_error",
+        Err(_) => "error",
+    }
+}
+
+/// `Retry-After` in delay-seconds form. HTTP dates are ignored and fall back
+/// to the jittered backoff.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    resp.headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::serve;
+    use axum::http::{HeaderMap, StatusCode as AxumStatusCode};
+    use axum::{routing::get, Router};
+    use reqwest::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn client(max_retries: u32) -> UpstreamClient {
+        UpstreamClient::new(
+            UpstreamConfig {
+                max_retries,
+                retry_base: Duration::from_millis(1),
+                ..Default::default()
+            },
+            Allowlist::from_hosts(["127.0.0.1"]),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let flaky = move || {
+            let counter = Arc::clone(&counter);
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert("retry-after", "0".parse().unwrap());
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => (AxumStatusCode::SERVICE_UNAVAILABLE, headers, "busy"),
+                    1 => (AxumStatusCode::BAD_GATEWAY, HeaderMap::new(), "down"),
+                    _ => (AxumStatusCode::OK, HeaderMap::new(), "ok"),
+                }
+            }
+        };
+        let missing = || async { AxumStatusCode::NOT_FOUND };
+
+        let router = Router::new()
+            .route("/flaky", get(flaky))
+            .route("/missing", get(missing));
+        let addr = serve(router).await;
+        let url = |path: &str| reqwest::Url::parse(&format!("http://{addr}{path}")).unwrap();
+
+        let upstream = client(2);
+        let resp = upstream
+            .send("test", upstream.get(url("/flaky")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Out of retries, the last 5xx is returned to the caller.
+        calls.store(0, Ordering::SeqCst);
+        let upstream = client(1);
+        let resp = upstream
+            .send("test", upstream.get(url("/flaky")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // 4xx responses are not retried.
+        let result = upstream
+            .get_json("test", upstream.get(url("/missing")))
+            .await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_hosts_outside_allowlist() {
+        let upstream = client(2);
+        for url in [
+            "http://localhost:9/",
+            "https://api.example.com/anime",
+            "https://127.0.0.1:9/",
+        ] {
+            let url = reqwest::Url::parse(url).unwrap();
+            let result = upstream.send("test", upstream.get(url.clone())).await;
+            assert!(matches!(result, Err(EnclaveError::Misconfigured(_))));
+            let result = upstream.probe(upstream.get(url)).await;
+            assert!(matches!(result, Err(e) if e.kind == ProbeErrorKind::Denied));
+        }
+        assert!(upstream.breakers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_errors_exhaust_retries() {
+        // Bind and drop a listener to get a port nothing listens on.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = client(2);
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let result = upstream.send("test", upstream.get(url.clone())).await;
+        assert!(matches!(result, Err(EnclaveError::UpstreamUnavailable(_))));
+
+        // The fifth consecutive failure opens the breaker, failing the next
+        // attempt fast.
+        let result = upstream.send("test", upstream.get(url)).await;
+        assert!(matches!(result, Err(EnclaveError::CircuitOpen(_))));
+        let breakers = upstream.breakers();
+        assert_eq!(
+            breakers["127.0.0.1"].state,
+            crate::limiter::BreakerState::Open
+        );
+        assert_eq!(breakers["127.0.0.1"].consecutive_failures, 5);
+    }
+
+    #[test]
+    fn test_worst_case_covers_retries() {
+        assert_eq!(
+            UpstreamConfig::default().worst_case(),
+            Duration::from_secs(40)
+        );
+        let config = UpstreamConfig {
+            max_retries: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.worst_case(), DEFAULT_REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let config = UpstreamConfig {
+            retry_base: Duration::from_millis(100),
+            retry_max: Duration::from_millis(250),
+            ..Default::default()
+        };
+        assert!(config.backoff(1) <= Duration::from_millis(100));
+        assert!((1..40).all(|retry| config.backoff(retry) <= Duration::from_millis(250)));
+    }
+}
@@ -0,0 +1,173 @@
+#[test_only]
+module odx::nautilus_payload_tests;
+
+use sui::bcs;
+use sui::ed25519;
+use odx::datatypes;
+
+// Test vectors produced by the Nautilus enclave (see `test_bcs_vector` in
+// nautilus-server/src/nautilus-server/src/apps/<provider>/mod.rs). Every
+// provider signs under its own intent scope, so a payload from one source
+// can't be decoded as another's.
+//
+// MAL payload is the BCS of `IntentMessage<MyMetrics>`:
+//   intent: u8, timestamp_ms: u64, data: { version: u8,
+//   binding: { ip_token_id: Option<address>, nonce: Option<u64> },
+//   media_kind: u8 (CATEGORY_ANIME | CATEGORY_MANGA | CATEGORY_MANHWA),
+//   mal_id: u64, title: String,
+//   external_average_rating: u64, external_popularity_rank: u64,
+//   external_member_count: u64, queried_name: String, match_confidence: u64,
+//   extended: { version: u8, rank: Option<u64>, num_scoring_users: u64,
+//     num_favorites: Option<u64>, list_status: Option<{ watching: u64,
+//     completed: u64, on_hold: u64, dropped: u64, plan_to_watch: u64 }>,
+//     status: String, start_date: Option<String>, num_episodes: Option<u64>,
+//     num_volumes: Option<u64>, num_chapters: Option<u64> } }
+const ENCLAVE_PUBLIC_KEY: vector<u8> = x"03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+const MAL_PAYLOAD: vector<u8> = x"000068e5cf8b01000006010b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b012a00000000000000001400000000000000064e617275746f1e03000000000000080000000000000020402c0000000000066e617275746f102700000000000002019402000000000000e0fd1c000000000001b03001000000000001905f01000000000060182300000000008038010000000000a08601000000000010090500000000000f66696e69736865645f616972696e67010a323030322d31302d303301dc000000000000000000";
+const MAL_SIGNATURE: vector<u8> = x"af48c66ccc425f72404f166d0b5392107e163124578dc37702d84188e2ee6db4c8353cafbad412469200e57da6940f7b076a41bec8c79083895d83a661f21c07";
+// AniList payload is the BCS of `IntentMessage<AniListMetrics>`:
+//   intent: u8, timestamp_ms: u64, data: { version: u8, binding,
+//   title: String, average_score: u64, popularity: u64, favourites: u64,
+//   trending: u64, queried_name: String }
+const ANILIST_PAYLOAD: vector<u8> = x"010068e5cf8b01000006010b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b012a00000000000000064e617275746f160300000000000059d1070000000000a05b0000000000001100000000000000066e617275746f";
+const ANILIST_SIGNATURE: vector<u8> = x"f2b3fdc925986ccbe5445ca7ba28d0ba1639e163d98f4cd77cb3fc6afc077cde79a641df10febdb74b34caad0c574cd88978971e54a06ca50758f086477faf09";
+// MangaDex payload is the BCS of `IntentMessage<MangaDexMetrics>`:
+//   intent: u8, timestamp_ms: u64, data: { version: u8, binding,
+//   manga_id: String, title: String, original_language: String,
+//   follows: u64, rating_bayesian: u64, rating_mean: u64,
+//   comment_count: u64, queried_name: String }
+const MANGADEX_PAYLOAD: vector<u8> = x"020068e5cf8b01000006010b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b012a000000000000002461316337633831372d346535392d343362372d393336352d303936373561313439613666094f6e65205069656365026a61c0c204000000000089030000000000009003000000000000dc05000000000000096f6e65207069656365";
+const MANGADEX_SIGNATURE: vector<u8> = x"009a183211c19a825a155a7d93975b2eb6688c9cfea839e0cb8e9b61ec53348e43f678ce0864d5a11681c59577a423400e705fde15e4edcb5b35bd7544539a0b";
+const IP_TOKEN_ID: address = @0x0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b;
+const NONCE: u64 = 42;
+
+const INTENT_MYANIMELIST: u8 = 0;
+const INTENT_ANILIST: u8 = 1;
+const INTENT_MANGADEX: u8 = 2;
+const METRICS_PAYLOAD_VERSION: u8 = 6;
+const EXTENDED_METRICS_VERSION: u8 = 2;
+const MATCH_CONFIDENCE_SCALE: u64 = 10000;
+
+#[test]
+fun test_decode_mal_payload() {
+    let mut reader = bcs::new(MAL_PAYLOAD);
+
+    assert!(reader.peel_u8() == INTENT_MYANIMELIST, 0);
+    assert!(reader.peel_u64() == 1700000000000, 1);
+    assert!(reader.peel_u8() == METRICS_PAYLOAD_VERSION, 2);
+
+    // Binding: a signature minted for another token or nonce must be rejected
+    assert!(reader.peel_option_address() == option::some(IP_TOKEN_ID), 10);
+    assert!(reader.peel_option_u64() == option::some(NONCE), 11);
+
+    // A manga measurement can't be passed off as an anime one
+    assert!(reader.peel_u8() == datatypes::category_anime(), 14);
+    assert!(reader.peel_u64() == 20, 12);
+    assert!(reader.peel_vec_u8() == b"Naruto", 3);
+
+    // Rating is already scaled by RATING_SCALE (798 = 7.98)
+    let average_rating = reader.peel_u64();
+    assert!(average_rating == 798, 4);
+    assert!(average_rating <= (datatypes::max_rating() as u64) * datatypes::rating_scale(), 5);
+
+    assert!(reader.peel_u64() == 8, 6);
+    assert!(reader.peel_u64() == 2900000, 7);
+    assert!(reader.peel_vec_u8() == b"naruto", 8);
+    // Exact title match
+    assert!(reader.peel_u64() == MATCH_CONFIDENCE_SCALE, 13);
+
+    // Extended metrics
+    assert!(reader.peel_u8() == EXTENDED_METRICS_VERSION, 20);
+    assert!(reader.peel_option_u64() == option::some(660), 21);
+    assert!(reader.peel_u64() == 1900000, 22);
+    assert!(reader.peel_option_u64() == option::some(78000), 23);
+    assert!(reader.peel_bool(), 29);
+    let watching = reader.peel_u64();
+    let completed = reader.peel_u64();
+    let on_hold = reader.peel_u64();
+    let dropped = reader.peel_u64();
+    let plan_to_watch = reader.peel_u64();
+    assert!(watching + completed + on_hold + dropped + plan_to_watch == 2900000, 24);
+    assert!(reader.peel_vec_u8() == b"finished_airing", 25);
+    // Option<String>: presence flag, then the string
+    assert!(reader.peel_bool(), 26);
+    assert!(reader.peel_vec_u8() == b"2002-10-03", 27);
+    assert!(reader.peel_option_u64() == option::some(220), 28);
+    assert!(reader.peel_option_u64().is_none(), 30);
+    assert!(reader.peel_option_u64().is_none(), 31);
+    assert!(reader.into_remainder_bytes().is_empty(), 9);
+}
+
+#[test]
+fun test_verify_mal_signature() {
+    assert!(ed25519::ed25519_verify(&MAL_SIGNATURE, &ENCLAVE_PUBLIC_KEY, &MAL_PAYLOAD), 0);
+}
+
+#[test]
+fun test_decode_anilist_payload() {
+    let mut reader = bcs::new(ANILIST_PAYLOAD);
+
+    assert!(reader.peel_u8() == INTENT_ANILIST, 0);
+    assert!(reader.peel_u64() == 1700000000000, 1);
+    assert!(reader.peel_u8() == METRICS_PAYLOAD_VERSION, 2);
+    assert!(reader.peel_option_address() == option::some(IP_TOKEN_ID), 10);
+    assert!(reader.peel_option_u64() == option::some(NONCE), 11);
+    assert!(reader.peel_vec_u8() == b"Naruto", 3);
+
+    // AniList's 79/100 rescaled to 0-10 and scaled by RATING_SCALE
+    let average_score = reader.peel_u64();
+    assert!(average_score == 790, 4);
+    assert!(average_score <= (datatypes::max_rating() as u64) * datatypes::rating_scale(), 5);
+
+    assert!(reader.peel_u64() == 512345, 6);
+    assert!(reader.peel_u64() == 23456, 7);
+    assert!(reader.peel_u64() == 17, 8);
+    assert!(reader.peel_vec_u8() == b"naruto", 12);
+    assert!(reader.into_remainder_bytes().is_empty(), 9);
+}
+
+#[test]
+fun test_verify_anilist_signature() {
+    assert!(ed25519::ed25519_verify(&ANILIST_SIGNATURE, &ENCLAVE_PUBLIC_KEY, &ANILIST_PAYLOAD), 0);
+}
+
+#[test]
+fun test_decode_mangadex_payload() {
+    let mut reader = bcs::new(MANGADEX_PAYLOAD);
+
+    assert!(reader.peel_u8() == INTENT_MANGADEX, 0);
+    assert!(reader.peel_u64() == 1700000000000, 1);
+    assert!(reader.peel_u8() == METRICS_PAYLOAD_VERSION, 2);
+    assert!(reader.peel_option_address() == option::some(IP_TOKEN_ID), 10);
+    assert!(reader.peel_option_u64() == option::some(NONCE), 11);
+    assert!(reader.peel_vec_u8() == b"a1c7c817-4e59-43b7-9365-09675a149a6f", 12);
+    assert!(reader.peel_vec_u8() == b"One Piece", 3);
+    assert!(reader.peel_vec_u8() == b"ja", 13);
+    assert!(reader.peel_u64() == 312000, 6);
+
+    // Ratings are already scaled by RATING_SCALE (905 = 9.05)
+    let rating_bayesian = reader.peel_u64();
+    assert!(rating_bayesian == 905, 4);
+    assert!(rating_bayesian <= (datatypes::max_rating() as u64) * datatypes::rating_scale(), 5);
+    assert!(reader.peel_u64() == 912, 7);
+
+    assert!(reader.peel_u64() == 1500, 8);
+    assert!(reader.peel_vec_u8() == b"one piece", 14);
+    assert!(reader.into_remainder_bytes().is_empty(), 9);
+}
+
+#[test]
+fun test_verify_mangadex_signature() {
+    assert!(ed25519::ed25519_verify(&MANGADEX_SIGNATURE, &ENCLAVE_PUBLIC_KEY, &MANGADEX_PAYLOAD), 0);
+}
+
+#[test]
+fun test_payload_sources_are_distinct() {
+    // Same token and nonce, but each payload is tagged with its own source
+    let mal = MAL_PAYLOAD;
+    let anilist = ANILIST_PAYLOAD;
+    let mangadex = MANGADEX_PAYLOAD;
+    assert!(mal[0] != anilist[0], 0);
+    assert!(anilist[0] != mangadex[0], 1);
+    assert!(mal[0] != mangadex[0], 2);
+}